@@ -0,0 +1,306 @@
+//! # Inspects the devices and files that get formatted
+
+use std::{
+    fs::{File, OpenOptions},
+    io::{ErrorKind, Read, Result as IoResult, Seek, SeekFrom, Write},
+    path::Path,
+};
+
+use crate::{
+    format::{SUPERBLOCK_OFFSETS, SUPERBLOCK_SIZE},
+    report::COMMON_PAGE_SIZE,
+};
+
+/// Magic numbers that identify a filesystem: its name, where the magic is, and what it is
+const SIGNATURES: [(&str, u64, &[u8]); 4] = [
+    ("btrfs", 0x1_0040, b"_BHRfS_M"),
+    ("ext4", 0x438, &[0x53, 0xef]),
+    ("xfs", 0, b"XFSB"),
+    ("vfat", 0x52, b"FAT32   "),
+];
+
+/// Look for the magic number of a known filesystem on `device`
+///
+/// Only finds the filesystems in [`SIGNATURES`]; the ext family is reported as ext4.
+pub(crate) fn probe_filesystem(device: &Path) -> IoResult<Option<&'static str>> {
+    let mut file = File::open(device)?;
+    for (name, offset, magic) in SIGNATURES {
+        let mut found = vec![0; magic.len()];
+        file.seek(SeekFrom::Start(offset))?;
+        match file.read_exact(&mut found) {
+            Ok(()) if found == magic => return Ok(Some(name)),
+            Ok(()) => {}
+            // Too short to hold this filesystem
+            Err(e) if e.kind() == ErrorKind::UnexpectedEof => {}
+            Err(e) => return Err(e),
+        }
+    }
+    Ok(None)
+}
+
+/// How much [`wipe_signatures`] zeroes at each end of a device
+pub(crate) const WIPE_SPAN: u64 = 1024 * 1024;
+
+/// Zero the start and end of `device`, and any btrfs superblock mirrors on it,
+/// so no stale signature is left for tools to find
+///
+/// Files keep their size. Block devices report a length of 0, so their size
+/// is found by seeking to the end instead.
+pub(crate) fn wipe_signatures(device: &Path) -> IoResult<()> {
+    let mut file = OpenOptions::new().write(true).open(device)?;
+    let size = file.seek(SeekFrom::End(0))?;
+    let mut ranges = vec![
+        (0, WIPE_SPAN.min(size)),
+        (size.saturating_sub(WIPE_SPAN), size),
+    ];
+    ranges.extend(
+        SUPERBLOCK_OFFSETS
+            .into_iter()
+            .filter(|&offset| offset < size)
+            .map(|offset| (offset, (offset + SUPERBLOCK_SIZE).min(size))),
+    );
+    let zeros = [0; 64 * 1024];
+    for (start, end) in ranges {
+        file.seek(SeekFrom::Start(start))?;
+        let mut left = end - start;
+        while left > 0 {
+            let chunk = left.min(zeros.len() as u64);
+            file.write_all(&zeros[..chunk as usize])?;
+            left -= chunk;
+        }
+    }
+    file.sync_all()
+}
+
+/// The page size of the running system
+#[cfg(target_os = "linux")]
+pub(crate) fn page_size() -> usize {
+    use std::os::raw::{c_int, c_long};
+    extern "C" {
+        fn sysconf(name: c_int) -> c_long;
+    }
+    /// The same on every Linux libc
+    const SC_PAGESIZE: c_int = 30;
+    // SAFETY: sysconf has no preconditions, and returns -1 on error
+    let page_size = unsafe { sysconf(SC_PAGESIZE) };
+    usize::try_from(page_size).unwrap_or(COMMON_PAGE_SIZE)
+}
+
+/// mkfs.btrfs doesn't run here anyway, so assume the common page size
+#[cfg(not(target_os = "linux"))]
+pub(crate) fn page_size() -> usize {
+    COMMON_PAGE_SIZE
+}
+
+#[cfg(target_os = "linux")]
+mod fcntl {
+    use std::os::raw::c_int;
+    extern "C" {
+        pub fn fcntl(fd: c_int, cmd: c_int, ...) -> c_int;
+    }
+    pub const F_GETFD: c_int = 1;
+    pub const F_SETFD: c_int = 2;
+    pub const FD_CLOEXEC: c_int = 1;
+}
+
+/// Whether `fd` is an open file descriptor in this process
+#[cfg(target_os = "linux")]
+pub(crate) fn fd_is_open(fd: std::os::unix::io::RawFd) -> bool {
+    // SAFETY: F_GETFD only reads the descriptor's flags, and fails with EBADF if it isn't open
+    unsafe { fcntl::fcntl(fd, fcntl::F_GETFD) != -1 }
+}
+
+/// Clear `fd`'s close-on-exec flag, so a child process inherits it
+///
+/// Only makes async-signal-safe calls, so it can run between fork and exec.
+#[cfg(target_os = "linux")]
+pub(crate) fn inherit_fd(fd: std::os::unix::io::RawFd) -> IoResult<()> {
+    use fcntl::*;
+    // SAFETY: F_GETFD and F_SETFD only touch the descriptor's flags, and report errors with -1
+    let flags = unsafe { fcntl(fd, F_GETFD) };
+    if flags == -1 || unsafe { fcntl(fd, F_SETFD, flags & !FD_CLOEXEC) } == -1 {
+        return Err(std::io::Error::last_os_error());
+    }
+    Ok(())
+}
+
+/// Check whether `device` is the block device backing `/`, or the disk that contains it
+#[cfg(target_os = "linux")]
+pub(crate) fn backs_root(device: &Path) -> IoResult<bool> {
+    use std::os::unix::fs::{FileTypeExt, MetadataExt};
+    let metadata = device.metadata()?;
+    if !metadata.file_type().is_block_device() {
+        return Ok(false);
+    }
+    let root = Path::new("/").metadata()?.dev();
+    Ok(metadata.rdev() == root || Some(metadata.rdev()) == parent_disk(root))
+}
+
+/// Whether `metadata` belongs to a block device
+#[cfg(unix)]
+pub(crate) fn is_block_device(metadata: &std::fs::Metadata) -> bool {
+    std::os::unix::fs::FileTypeExt::is_block_device(&metadata.file_type())
+}
+
+/// There are no block devices to find on this platform
+#[cfg(not(unix))]
+pub(crate) fn is_block_device(_metadata: &std::fs::Metadata) -> bool {
+    false
+}
+
+/// The size of `device` in bytes: the length of a file, or the capacity of a block device
+pub(crate) fn device_size(device: &Path) -> IoResult<u64> {
+    let metadata = device.metadata()?;
+    if !is_block_device(&metadata) {
+        return Ok(metadata.len());
+    }
+    block_device_size(&File::open(device)?)
+}
+
+/// Where `_IOC` puts the direction bits: above a 13-bit size on these architectures
+#[cfg(all(
+    target_os = "linux",
+    any(
+        target_arch = "powerpc",
+        target_arch = "powerpc64",
+        target_arch = "mips",
+        target_arch = "mips64",
+        target_arch = "mips32r6",
+        target_arch = "mips64r6",
+        target_arch = "sparc",
+        target_arch = "sparc64"
+    )
+))]
+const IOC_DIRSHIFT: u32 = 29;
+
+/// Where `_IOC` puts the direction bits: above a 14-bit size everywhere else
+#[cfg(all(
+    target_os = "linux",
+    not(any(
+        target_arch = "powerpc",
+        target_arch = "powerpc64",
+        target_arch = "mips",
+        target_arch = "mips64",
+        target_arch = "mips32r6",
+        target_arch = "mips64r6",
+        target_arch = "sparc",
+        target_arch = "sparc64"
+    ))
+))]
+const IOC_DIRSHIFT: u32 = 30;
+
+/// `_IOR(0x12, 114, size_t)`, from linux/fs.h. `_IOC_READ` is 2 on every architecture.
+#[cfg(target_os = "linux")]
+pub(crate) const BLKGETSIZE64: u64 =
+    (2 << IOC_DIRSHIFT) | ((std::mem::size_of::<usize>() as u64) << 16) | (0x12 << 8) | 114;
+
+/// Ask the kernel how big a block device is, or seek to its end if it won't say
+#[cfg(target_os = "linux")]
+fn block_device_size(mut device: &File) -> IoResult<u64> {
+    use std::os::{
+        raw::{c_int, c_ulong},
+        unix::io::AsRawFd,
+    };
+    extern "C" {
+        fn ioctl(fd: c_int, request: c_ulong, ...) -> c_int;
+    }
+    let mut size: u64 = 0;
+    // SAFETY: BLKGETSIZE64 writes a u64 through the pointer, which outlives the call
+    if unsafe {
+        ioctl(
+            device.as_raw_fd(),
+            BLKGETSIZE64 as c_ulong,
+            &mut size as *mut u64,
+        )
+    } == -1
+    {
+        return device.seek(SeekFrom::End(0));
+    }
+    Ok(size)
+}
+
+/// Block devices can at least be seeked to their end
+#[cfg(not(target_os = "linux"))]
+fn block_device_size(mut device: &File) -> IoResult<u64> {
+    device.seek(SeekFrom::End(0))
+}
+
+/// Formatting can't clobber `/` through a path on a platform without block devices
+#[cfg(not(target_os = "linux"))]
+pub(crate) fn backs_root(_device: &Path) -> IoResult<bool> {
+    Ok(false)
+}
+
+/// Where sysfs is mounted
+#[cfg(target_os = "linux")]
+const SYSFS: &str = "/sys";
+
+/// Check whether `device` is a spinning block device. `None` if it's not a block device,
+/// or sysfs doesn't say.
+#[cfg(target_os = "linux")]
+pub(crate) fn is_rotational(device: &Path) -> IoResult<Option<bool>> {
+    use std::os::unix::fs::{FileTypeExt, MetadataExt};
+    let metadata = device.metadata()?;
+    if !metadata.file_type().is_block_device() {
+        return Ok(None);
+    }
+    Ok(rotational_in(Path::new(SYSFS), metadata.rdev()))
+}
+
+/// Block devices can't be told apart on a platform without sysfs
+#[cfg(not(target_os = "linux"))]
+pub(crate) fn is_rotational(_device: &Path) -> IoResult<Option<bool>> {
+    Ok(None)
+}
+
+/// Read `queue/rotational` for the block device `rdev`, from the sysfs at `sysfs`
+#[cfg(target_os = "linux")]
+pub(crate) fn rotational_in(sysfs: &Path, rdev: u64) -> Option<bool> {
+    let (major, minor) = split_dev(rdev);
+    let dev =
+        std::fs::canonicalize(sysfs.join("dev/block").join(format!("{major}:{minor}"))).ok()?;
+    // Partitions don't have a queue of their own, but share their disk's
+    let queue = [dev.join("queue"), dev.parent()?.join("queue")]
+        .into_iter()
+        .find(|queue| queue.is_dir())?;
+    match std::fs::read_to_string(queue.join("rotational"))
+        .ok()?
+        .trim()
+    {
+        "0" => Some(false),
+        "1" => Some(true),
+        _ => None,
+    }
+}
+
+/// Find the whole disk a partition belongs to, through sysfs
+#[cfg(target_os = "linux")]
+fn parent_disk(partition: u64) -> Option<u64> {
+    let (major, minor) = split_dev(partition);
+    let sysfs = Path::new("/sys/dev/block").join(format!("{major}:{minor}"));
+    // Only partitions have a `partition` file
+    if !sysfs.join("partition").exists() {
+        return None;
+    }
+    let disk = std::fs::canonicalize(sysfs).ok()?.parent()?.join("dev");
+    let dev = std::fs::read_to_string(disk).ok()?;
+    let (major, minor) = dev.trim().split_once(':')?;
+    Some(join_dev(major.parse().ok()?, minor.parse().ok()?))
+}
+
+/// Split a Linux `dev_t` into its major and minor numbers
+#[cfg(target_os = "linux")]
+fn split_dev(dev: u64) -> (u64, u64) {
+    let major = ((dev >> 32) & 0xffff_f000) | ((dev >> 8) & 0x0000_0fff);
+    let minor = ((dev >> 12) & 0xffff_ff00) | (dev & 0x0000_00ff);
+    (major, minor)
+}
+
+/// Join major and minor numbers into a Linux `dev_t`
+#[cfg(target_os = "linux")]
+pub(crate) fn join_dev(major: u64, minor: u64) -> u64 {
+    ((major & 0xffff_f000) << 32)
+        | ((major & 0x0000_0fff) << 8)
+        | ((minor & 0xffff_ff00) << 12)
+        | (minor & 0x0000_00ff)
+}
@@ -29,7 +29,7 @@
 //!     .mixed()?              // true if called
 //!     .no_discard()?         // true if called
 //!     .nodesize(4096_usize)?
-//!     .rootdir("./testdir")?
+//!     .rootdir("./src")?
 //!     .runtime_features(["quota"])?
 //!     .sectorsize(4096_usize)?
 //!     .shrink()?             // true if called
@@ -43,21 +43,85 @@
 //! [`btrfs-progs`]: https://btrfs.readthedocs.io/en/latest/Introduction.html
 //! [`mkfs.btrfs`]: https://btrfs.readthedocs.io/en/latest/mkfs.btrfs.html
 
-use crate::{Error::*, Result};
+use crate::{
+    device, random,
+    report::{self, FormatReport},
+    validate::{self, Violation},
+    Error::*,
+    Result,
+};
 use std::{
-    ffi::OsString,
-    io::Result as IoResult,
-    path::Path,
-    process::{Command, Output},
+    borrow::Cow,
+    ffi::{OsStr, OsString},
+    fs::{File, OpenOptions},
+    io::{BufRead, BufReader, Read, Result as IoResult, Write},
+    path::{Path, PathBuf},
+    process::{Child, Command, ExitStatus, Output, Stdio},
+    sync::{
+        atomic::{AtomicBool, Ordering},
+        mpsc::{self, Sender},
+        Arc,
+    },
+    thread::{self, JoinHandle},
+    time::{Duration, Instant},
 };
 
 pub const RUNTIME_FEATURES: [&str; 2] = ["quota", "free-space-tree"];
 
+/// Where btrfs writes its superblock and the mirrors of it, in bytes from the start of the device
+pub const SUPERBLOCK_OFFSETS: [u64; 3] = [64 << 10, 64 << 20, 256 << 30];
+
+/// How much room a superblock takes up, whatever the sector size
+pub const SUPERBLOCK_SIZE: u64 = 4096;
+
+/// The largest `nodesize` [`FormatterOptions::nodesize`] accepts
+pub const MAX_NODESIZE: usize = 16384;
+
+/// The largest `sectorsize` [`FormatterOptions::sectorsize`] accepts
+pub const MAX_SECTORSIZE: usize = 65536;
+
+/// The mkfs.btrfs manual, which setter errors link to
+pub const MANUAL_BASE: &str = "https://btrfs.readthedocs.io/en/latest/mkfs.btrfs.html";
+
+// Sections of the manual at MANUAL_BASE. Its options have no stable anchors of their own.
+const OPTIONS_ANCHOR: &str = "options";
+const PROFILES_ANCHOR: &str = "profiles";
+
+/// Why `shrink` without a `rootdir` is a likely mistake
+pub(crate) const SHRINK_WITHOUT_ROOTDIR: &str = "shrink is ignored by mkfs.btrfs without a rootdir";
+
+/// The binary a [`Formatter`] runs, unless told otherwise
+const DEFAULT_BINARY: &str = "mkfs.btrfs";
+
+/// The binary [`Formatter::format_verified`] checks filesystems with, unless told otherwise
+const DEFAULT_CHECK_BINARY: &str = "btrfs";
+
+/// Extra space [`FormatterOptions::rootdir_sized`] leaves for metadata, in percent of the content size
+pub const DEFAULT_ROOTDIR_OVERHEAD: u64 = 50;
+
+/// The smallest `byte_count` [`FormatterOptions::rootdir_sized`] will pick,
+/// since mkfs.btrfs refuses to create very small filesystems
+pub const MIN_ROOTDIR_BYTE_COUNT: u64 = 128 * 1024 * 1024;
+
+/// The smallest `byte_count` [`FormatterOptions::try_build`] accepts for most profiles.
+///
+/// A rough lower bound: mkfs.btrfs needs more than this, but anything smaller is sure to fail.
+pub const MIN_BYTE_COUNT: u64 = 16 * 1024 * 1024;
+
+/// The smallest `byte_count` [`FormatterOptions::try_build`] accepts with a `dup` profile,
+/// which keeps two copies of everything on one device.
+///
+/// The minimum mkfs.btrfs reports for its default `dup` metadata.
+pub const MIN_BYTE_COUNT_DUP: u64 = 114_294_784;
+
+/// How big [`FormatterOptions::to_shell_script`] makes the image, when `byte_count` isn't set
+pub const DEFAULT_IMAGE_SIZE: u64 = 512 * 1024 * 1024;
+
 /// Represents the set of valid (meta)data profiles.
 /// ```sh
 /// mkfs.btrfs --data ( raid0 | raid1 | ... )
 /// ```
-#[derive(Copy, Clone, Debug)]
+#[derive(Copy, Clone, Debug, Hash, PartialEq, Eq, PartialOrd, Ord)]
 pub enum DataProfile {
     Raid0,
     Raid1,
@@ -70,10 +134,57 @@ pub enum DataProfile {
     Dup,
 }
 
-impl std::fmt::Display for DataProfile {
-    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+impl DataProfile {
+    /// Every profile, in declaration order
+    pub const ALL: [DataProfile; 9] = [
+        DataProfile::Raid0,
+        DataProfile::Raid1,
+        DataProfile::Raid1c3,
+        DataProfile::Raid1c4,
+        DataProfile::Raid5,
+        DataProfile::Raid6,
+        DataProfile::Raid10,
+        DataProfile::Single,
+        DataProfile::Dup,
+    ];
+
+    /// The smallest `byte_count` [`FormatterOptions::try_build`] accepts with this profile,
+    /// outside of mixed mode
+    ///
+    /// # Examples
+    /// ```
+    /// use mkfs_btrfs_rs::format::{DataProfile, MIN_BYTE_COUNT_DUP};
+    /// assert_eq!(MIN_BYTE_COUNT_DUP, DataProfile::Dup.min_byte_count());
+    /// ```
+    pub const fn min_byte_count(&self) -> u64 {
+        match *self {
+            DataProfile::Dup => MIN_BYTE_COUNT_DUP,
+            _ => MIN_BYTE_COUNT,
+        }
+    }
+
+    /// The fewest devices mkfs.btrfs will create a filesystem with this profile on
+    ///
+    /// # Examples
+    /// ```
+    /// use mkfs_btrfs_rs::format::DataProfile;
+    /// assert_eq!(4, DataProfile::Raid10.min_devices());
+    /// ```
+    pub const fn min_devices(&self) -> usize {
+        use DataProfile::*;
+        match *self {
+            Single | Dup => 1,
+            Raid0 | Raid1 | Raid5 => 2,
+            Raid1c3 | Raid6 => 3,
+            Raid1c4 | Raid10 => 4,
+        }
+    }
+}
+
+impl AsRef<str> for DataProfile {
+    fn as_ref(&self) -> &str {
         use DataProfile::*;
-        let data_profile: &str = match *self {
+        match *self {
             Raid0 => "raid0",
             Raid1 => "raid1",
             Raid1c3 => "raid1c3",
@@ -83,8 +194,47 @@ impl std::fmt::Display for DataProfile {
             Raid10 => "raid10",
             Single => "single",
             Dup => "dup",
-        };
-        write!(f, "{data_profile}")
+        }
+    }
+}
+
+impl std::fmt::Display for DataProfile {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        f.write_str(self.as_ref())
+    }
+}
+
+impl From<DataProfile> for String {
+    fn from(data_profile: DataProfile) -> Self {
+        data_profile.as_ref().to_owned()
+    }
+}
+
+impl std::str::FromStr for DataProfile {
+    type Err = crate::Error;
+    fn from_str(s: &str) -> Result<Self> {
+        use DataProfile::*;
+        Ok(match s.to_ascii_lowercase().as_str() {
+            "raid0" => Raid0,
+            "raid1" => Raid1,
+            "raid1c3" => Raid1c3,
+            "raid1c4" => Raid1c4,
+            "raid5" => Raid5,
+            "raid6" => Raid6,
+            "raid10" => Raid10,
+            "single" => Single,
+            "dup" => Dup,
+            _ => {
+                return Err(rejected(
+                    "profile",
+                    s,
+                    format!(
+                        "profile ( = {s} )\nMust be one of {}",
+                        DataProfile::ALL.map(String::from).join(", ")
+                    ),
+                ))
+            }
+        })
     }
 }
 
@@ -92,26 +242,265 @@ impl std::fmt::Display for DataProfile {
 /// ```sh
 /// mkfs.btrfs --checksum [ crc32c | xxhash | sha256 | blake2 ]
 /// ```
-#[derive(Clone, Copy, Debug)]
+/// Defaults to [`Crc32c`](ChecksumAlgorithm::Crc32c), like mkfs.btrfs.
+#[derive(Clone, Copy, Debug, Default, Hash, PartialEq, Eq, PartialOrd, Ord)]
 pub enum ChecksumAlgorithm {
+    /// 32 bits. The fastest (hardware-accelerated on most CPUs),
+    /// and supported by every kernel, but collisions are likely.
+    #[default]
     Crc32c,
+    /// 64 bits. Nearly as fast, with far fewer collisions. Needs Linux 5.5+.
     XxHash,
+    /// 256 bits. Cryptographically strong, but the slowest unless the CPU
+    /// accelerates it. Needs Linux 5.5+.
     Sha256,
+    /// 256 bits. Cryptographically strong, and faster than sha256 in software.
+    /// Needs Linux 5.5+.
     Blake2,
 }
-impl std::fmt::Display for ChecksumAlgorithm {
-    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+impl ChecksumAlgorithm {
+    /// Every checksum algorithm, in declaration order
+    pub const ALL: [ChecksumAlgorithm; 4] = [
+        ChecksumAlgorithm::Crc32c,
+        ChecksumAlgorithm::XxHash,
+        ChecksumAlgorithm::Sha256,
+        ChecksumAlgorithm::Blake2,
+    ];
+
+    /// How many bytes each checksum takes up in the metadata
+    ///
+    /// # Examples
+    /// ```
+    /// use mkfs_btrfs_rs::format::ChecksumAlgorithm;
+    /// assert_eq!(32, ChecksumAlgorithm::Blake2.size());
+    /// ```
+    pub const fn size(&self) -> usize {
+        use ChecksumAlgorithm::*;
+        match *self {
+            Crc32c => 4,
+            XxHash => 8,
+            Sha256 | Blake2 => 32,
+        }
+    }
+}
+
+impl AsRef<str> for ChecksumAlgorithm {
+    fn as_ref(&self) -> &str {
         use ChecksumAlgorithm::*;
-        let algorithm: &str = match *self {
+        match *self {
             Crc32c => "crc32c",
             XxHash => "xxhash",
             Sha256 => "sha256",
             Blake2 => "blake2",
+        }
+    }
+}
+
+impl std::fmt::Display for ChecksumAlgorithm {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        f.write_str(self.as_ref())
+    }
+}
+
+impl From<ChecksumAlgorithm> for String {
+    fn from(algorithm: ChecksumAlgorithm) -> Self {
+        algorithm.as_ref().to_owned()
+    }
+}
+
+impl std::str::FromStr for ChecksumAlgorithm {
+    type Err = crate::Error;
+    fn from_str(s: &str) -> Result<Self> {
+        use ChecksumAlgorithm::*;
+        Ok(match s.to_ascii_lowercase().as_str() {
+            "crc32c" => Crc32c,
+            "xxhash" => XxHash,
+            "sha256" => Sha256,
+            "blake2" => Blake2,
+            _ => {
+                return Err(rejected(
+                    "checksum",
+                    s,
+                    format!(
+                        "checksum ( = {s} )\nMust be one of {}",
+                        ChecksumAlgorithm::ALL.map(String::from).join(", ")
+                    ),
+                ))
+            }
+        })
+    }
+}
+
+/// Represents the set of known mkfs-time features.
+/// ```sh
+/// mkfs.btrfs --features ( mixed-bg | no-holes | ^extref | ... )
+/// ```
+/// Use [`MkfsFeature::disable`] to unset a feature that's enabled by default.
+#[derive(Clone, Debug, PartialEq, Eq)]
+pub enum MkfsFeature {
+    MixedBg,
+    Quota,
+    Extref,
+    Raid56,
+    SkinnyMetadata,
+    NoHoles,
+    FreeSpaceTree,
+    Raid1c34,
+    Zoned,
+    BlockGroupTree,
+    Squota,
+    /// Unsets the inner feature (`^feature`)
+    Unset(Box<MkfsFeature>),
+}
+
+impl MkfsFeature {
+    /// Unset this feature, prefixing it with '^'.
+    ///
+    /// # Examples
+    /// ```
+    /// use mkfs_btrfs_rs::format::MkfsFeature;
+    /// assert_eq!("^no-holes", MkfsFeature::NoHoles.disable().to_string());
+    /// ```
+    pub fn disable(self) -> Self {
+        MkfsFeature::Unset(Box::new(self))
+    }
+}
+
+impl std::fmt::Display for MkfsFeature {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        use MkfsFeature::*;
+        let feature: &str = match self {
+            MixedBg => "mixed-bg",
+            Quota => "quota",
+            Extref => "extref",
+            Raid56 => "raid56",
+            SkinnyMetadata => "skinny-metadata",
+            NoHoles => "no-holes",
+            FreeSpaceTree => "free-space-tree",
+            Raid1c34 => "raid1c34",
+            Zoned => "zoned",
+            BlockGroupTree => "block-group-tree",
+            Squota => "squota",
+            Unset(feature) => return write!(f, "^{feature}"),
         };
-        write!(f, "{algorithm}")
+        write!(f, "{feature}")
+    }
+}
+
+/// Represents the ways [`FormatterOptions::uuid_mode`] can generate a UUID.
+#[derive(Copy, Clone, Debug, PartialEq, Eq)]
+pub enum UuidMode {
+    /// A random (version 4) UUID
+    Random,
+    /// A time-based (version 1) UUID, with a random node ID
+    Time,
+}
+
+/// Curated sets of options for common scenarios, applied with [`FormatterOptions::preset`].
+///
+/// | Preset          | checksum | features                             | nodesize | other                       |
+/// |-----------------|----------|--------------------------------------|----------|-----------------------------|
+/// | `Minimal`       |          |                                      | 4096     | `mixed`, `single` profile, sectorsize 4096 |
+/// | `Server`        | xxhash   | no-holes, free-space-tree, block-group-tree | 16384 | `dup` metadata      |
+/// | `SsdOptimized`  | crc32c   | no-holes, free-space-tree            | 16384    | discard left on             |
+/// | `Compatibility` | crc32c   | ^no-holes, ^free-space-tree          | 16384    | `no_discard`                |
+#[derive(Copy, Clone, Debug, Hash, PartialEq, Eq, PartialOrd, Ord)]
+pub enum Preset {
+    /// The smallest footprint, for tiny images: data and metadata share block groups
+    Minimal,
+    /// Fewer checksum collisions and faster mounts, for large long-lived filesystems.
+    /// Needs Linux 6.1+ for block-group-tree.
+    Server,
+    /// The fastest checksum, with the initial discard mkfs.btrfs does by default
+    SsdOptimized,
+    /// Mountable by old kernels, and safe on devices that choke on discard
+    Compatibility,
+}
+
+impl Preset {
+    /// Every preset, in declaration order
+    pub const ALL: [Preset; 4] = [
+        Preset::Minimal,
+        Preset::Server,
+        Preset::SsdOptimized,
+        Preset::Compatibility,
+    ];
+}
+
+impl AsRef<str> for Preset {
+    fn as_ref(&self) -> &str {
+        use Preset::*;
+        match *self {
+            Minimal => "minimal",
+            Server => "server",
+            SsdOptimized => "ssd-optimized",
+            Compatibility => "compatibility",
+        }
+    }
+}
+
+impl std::fmt::Display for Preset {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        f.write_str(self.as_ref())
+    }
+}
+
+impl From<Preset> for String {
+    fn from(preset: Preset) -> Self {
+        preset.as_ref().to_owned()
+    }
+}
+
+/// Parses the names [`Display`](std::fmt::Display) prints, like `ssd-optimized`, ignoring case.
+impl std::str::FromStr for Preset {
+    type Err = crate::Error;
+    fn from_str(s: &str) -> Result<Self> {
+        use Preset::*;
+        Ok(match s.to_ascii_lowercase().as_str() {
+            "minimal" => Minimal,
+            "server" => Server,
+            "ssd-optimized" => SsdOptimized,
+            "compatibility" => Compatibility,
+            _ => {
+                return Err(rejected(
+                    "preset",
+                    s,
+                    format!(
+                        "preset ( = {s} )\nMust be one of {}",
+                        Preset::ALL.map(String::from).join(", ")
+                    ),
+                ))
+            }
+        })
     }
 }
 
+/// What a check does when it finds a likely mistake that mkfs.btrfs wouldn't refuse,
+/// e.g. [`FormatterOptions::page_size_check`].
+#[derive(Copy, Clone, Debug, Default, Hash, PartialEq, Eq, PartialOrd, Ord)]
+pub enum Strictness {
+    /// Don't check
+    Ignore,
+    /// Add a warning to [`Formatter::preflight`]
+    #[default]
+    Warn,
+    /// Fail [`FormatterOptions::validate`] (and so [`try_build`](FormatterOptions::try_build))
+    Deny,
+}
+
+/// What [`FormatterOptions::rootdirs`] does when two rootdirs have a file at the same path
+#[cfg(feature = "tempfile")]
+#[derive(Copy, Clone, Debug, Default, Hash, PartialEq, Eq, PartialOrd, Ord)]
+pub enum ConflictPolicy {
+    /// Keep the file from the earlier rootdir
+    FirstWins,
+    /// Replace it with the file from the later rootdir
+    LastWins,
+    /// Reject the rootdirs
+    #[default]
+    Error,
+}
+
 /// It's like an Option, but THICC
 #[derive(Clone, Debug, Default)]
 enum FormatOpt {
@@ -130,11 +519,12 @@ impl std::fmt::Display for FormatOpt {
 }
 
 /// Represents options for [`mkfs.btrfs`](https://btrfs.readthedocs.io/en/latest/mkfs.btrfs.html#options).
-#[derive(Clone, Debug, Default)]
+#[derive(Clone, Debug, Default, PartialEq, Eq)]
 pub struct FormatterOptions {
     byte_count: Option<OsString>,       // Uint
     checksum: Option<OsString>,         // Csum
     data: Option<OsString>,             // Data
+    device_uuid: Option<OsString>,      // Uuid
     features: Option<OsString>,         // List
     force: Option<OsString>,            // Bool
     label: Option<OsString>,            // Text
@@ -147,10 +537,248 @@ pub struct FormatterOptions {
     sectorsize: Option<OsString>,       // Uint
     shrink: Option<OsString>,           // Bool
     uuid: Option<OsString>,             // Uuid
+    extra_args: Vec<OsString>,          // Raw
+    values: OptionValues,
+}
+
+/// Names each option in [`FormatterOptions`].
+#[derive(Copy, Clone, Debug, Hash, PartialEq, Eq, PartialOrd, Ord)]
+pub enum Field {
+    ByteCount,
+    Checksum,
+    Data,
+    DeviceUuid,
+    Features,
+    Force,
+    Label,
+    Metadata,
+    Mixed,
+    NoDiscard,
+    Nodesize,
+    Rootdir,
+    RuntimeFeatures,
+    Sectorsize,
+    Shrink,
+    Uuid,
+}
+impl Field {
+    /// Every field, in the order their args are passed to mkfs.btrfs
+    pub const ALL: [Field; 16] = [
+        Field::ByteCount,
+        Field::Checksum,
+        Field::Data,
+        Field::DeviceUuid,
+        Field::Features,
+        Field::Force,
+        Field::Label,
+        Field::Metadata,
+        Field::Mixed,
+        Field::NoDiscard,
+        Field::Nodesize,
+        Field::Rootdir,
+        Field::RuntimeFeatures,
+        Field::Sectorsize,
+        Field::Shrink,
+        Field::Uuid,
+    ];
+}
+
+/// An option that differs between two [`FormatterOptions`], from [`FormatterOptions::diff`]
+#[derive(Clone, Debug, PartialEq, Eq)]
+pub struct FieldChange {
+    pub field: Field,
+    /// The rendered arg before, or `None` if it wasn't set
+    pub old: Option<OsString>,
+    /// The rendered arg after, or `None` if it's been unset
+    pub new: Option<OsString>,
+}
+
+/// Typed copies of some options, for cross-field validation
+#[derive(Clone, Debug, Default, Hash, PartialEq, Eq, PartialOrd, Ord)]
+struct OptionValues {
+    byte_count: Option<u64>,
+    checksum: Option<ChecksumAlgorithm>,
+    data: Option<DataProfile>,
+    features: Vec<String>,
+    metadata: Option<DataProfile>,
+    nodesize: Option<usize>,
+    sectorsize: Option<usize>,
+    deterministic: bool,
+    allow_dangerous: bool,
+    allow_empty_label: bool,
+    base_dir: Option<PathBuf>,
+    /// Set on mkfs.btrfs, in order, after the C locale
+    env: Vec<(OsString, OsString)>,
+    native_locale: bool,
+    log_to: Option<PathBuf>,
+    wipe_signatures: bool,
+    fill_device: bool,
+    device_uuid: Option<String>,
+    /// Where [`FormatterOptions::rootdir_from_tar`] or [`FormatterOptions::rootdirs`]
+    /// staged the rootdir, kept until the last clone is dropped
+    #[cfg(feature = "tempfile")]
+    staged: Option<Arc<crate::temp::TempDir>>,
+    page_size_check: Strictness,
+    /// Unset means [`Strictness::Deny`]
+    shrink_check: Option<Strictness>,
+    checksum_check: Strictness,
+    auto_discard: bool,
+}
+
+impl OptionValues {
+    /// How the sector size differs from this system's page size, if it does
+    fn page_size_mismatch(&self) -> Option<Violation> {
+        let page_size = device::page_size();
+        self.sectorsize
+            .filter(|&sectorsize| sectorsize != page_size)
+            .map(|sectorsize| Violation::PageSizeMismatch {
+                sectorsize,
+                page_size,
+            })
+    }
+    /// The checksum and sector size, if a 32-byte checksum is paired with the smallest sector size
+    fn heavy_checksum(&self) -> Option<(ChecksumAlgorithm, usize)> {
+        let checksum = self.checksum.filter(|checksum| checksum.size() >= 32)?;
+        let sectorsize = self.sectorsize.filter(|&sectorsize| sectorsize == 4096)?;
+        Some((checksum, sectorsize))
+    }
+    /// How strictly `shrink` without a `rootdir` is checked
+    fn shrink_check(&self) -> Strictness {
+        self.shrink_check.unwrap_or(Strictness::Deny)
+    }
+    /// Join a relative path onto the base dir, if there is one
+    fn resolve<'a>(&self, path: &'a Path) -> Cow<'a, Path> {
+        match &self.base_dir {
+            Some(base_dir) if path.is_relative() => Cow::Owned(base_dir.join(path)),
+            _ => Cow::Borrowed(path),
+        }
+    }
 }
 
 impl FormatterOptions {
+    /// Read options from environment variables, through the same validation as the setters.
+    ///
+    /// Unset or empty variables are skipped. Lists are comma-separated, and flags are
+    /// set by `1`, `true` or `yes` (and left unset by `0`, `false` or `no`.)
+    /// The preset is applied first, so the other variables override it.
+    ///
+    /// | Variable                         | Option                                      |
+    /// |----------------------------------|---------------------------------------------|
+    /// | `MKFS_BTRFS_BYTE_COUNT`          | [`byte_count`](Self::byte_count)             |
+    /// | `MKFS_BTRFS_CHECKSUM`            | [`checksum`](Self::checksum)                 |
+    /// | `MKFS_BTRFS_DATA`                | [`data`](Self::data)                         |
+    /// | `MKFS_BTRFS_FEATURES`            | [`features`](Self::features)                 |
+    /// | `MKFS_BTRFS_FORCE`               | [`force`](Self::force)                       |
+    /// | `MKFS_BTRFS_LABEL`               | [`label`](Self::label)                       |
+    /// | `MKFS_BTRFS_METADATA`            | [`metadata`](Self::metadata)                 |
+    /// | `MKFS_BTRFS_MIXED`               | [`mixed`](Self::mixed)                       |
+    /// | `MKFS_BTRFS_NO_DISCARD`          | [`no_discard`](Self::no_discard)             |
+    /// | `MKFS_BTRFS_NODESIZE`            | [`nodesize`](Self::nodesize)                 |
+    /// | `MKFS_BTRFS_PRESET`              | [`preset`](Self::preset)                     |
+    /// | `MKFS_BTRFS_ROOTDIR`             | [`rootdir`](Self::rootdir)                   |
+    /// | `MKFS_BTRFS_RUNTIME_FEATURES`    | [`runtime_features`](Self::runtime_features) |
+    /// | `MKFS_BTRFS_SECTORSIZE`          | [`sectorsize`](Self::sectorsize)             |
+    /// | `MKFS_BTRFS_SHRINK`              | [`shrink`](Self::shrink)                     |
+    /// | `MKFS_BTRFS_UUID`                | [`uuid`](Self::uuid)                         |
+    ///
+    /// # Examples
+    /// ```
+    /// # use mkfs_btrfs_rs::Error;
+    /// use mkfs_btrfs_rs::format::FormatterOptions;
+    /// let formatter = FormatterOptions::from_env()?
+    ///     .build();
+    /// # Ok::<(), Error>(())
+    /// ```
+    pub fn from_env() -> Result<Self> {
+        let mut options = Self::default();
+        if let Some(preset) = env_var("MKFS_BTRFS_PRESET")? {
+            options = options.preset(preset.parse()?)?;
+        }
+        if let Some(byte_count) = env_number("MKFS_BTRFS_BYTE_COUNT")? {
+            options = options.byte_count(byte_count)?;
+        }
+        if let Some(checksum) = env_var("MKFS_BTRFS_CHECKSUM")? {
+            options = options.checksum(checksum.parse()?)?;
+        }
+        if let Some(data) = env_var("MKFS_BTRFS_DATA")? {
+            options = options.data(data.parse()?)?;
+        }
+        if let Some(features) = env_var("MKFS_BTRFS_FEATURES")? {
+            options = options.features(features.split(','))?;
+        }
+        if env_flag("MKFS_BTRFS_FORCE")? {
+            options = options.force()?;
+        }
+        if let Some(label) = env_var("MKFS_BTRFS_LABEL")? {
+            options = options.label(&label)?;
+        }
+        if let Some(metadata) = env_var("MKFS_BTRFS_METADATA")? {
+            options = options.metadata(metadata.parse()?)?;
+        }
+        if env_flag("MKFS_BTRFS_MIXED")? {
+            options = options.mixed()?;
+        }
+        if env_flag("MKFS_BTRFS_NO_DISCARD")? {
+            options = options.no_discard()?;
+        }
+        if let Some(nodesize) = env_number("MKFS_BTRFS_NODESIZE")? {
+            options = options.nodesize(nodesize)?;
+        }
+        if let Some(rootdir) = env_var("MKFS_BTRFS_ROOTDIR")? {
+            options = options.rootdir(rootdir)?;
+        }
+        if let Some(features) = env_var("MKFS_BTRFS_RUNTIME_FEATURES")? {
+            options = options.runtime_features(features.split(','))?;
+        }
+        if let Some(sectorsize) = env_number("MKFS_BTRFS_SECTORSIZE")? {
+            options = options.sectorsize(sectorsize)?;
+        }
+        if env_flag("MKFS_BTRFS_SHRINK")? {
+            options = options.shrink()?;
+        }
+        if let Some(uuid) = env_var("MKFS_BTRFS_UUID")? {
+            options = options.uuid(&uuid)?;
+        }
+        Ok(options)
+    }
+    /// Resolve relative rootdir and device paths against `dir`, instead of the current directory
+    ///
+    /// Only applies to paths given after it's set. `dir` must exist.
+    ///
+    /// # Examples
+    /// ```
+    /// # use mkfs_btrfs_rs::Error;
+    /// use mkfs_btrfs_rs::format::Formatter;
+    /// Formatter::options()
+    ///     .base_dir(env!("CARGO_MANIFEST_DIR"))?
+    ///     .rootdir("src")?;
+    /// # Ok::<(), Error>(())
+    /// ```
+    pub fn base_dir<P: AsRef<Path>>(mut self, dir: P) -> Result<Self> {
+        let dir = dir.as_ref();
+        if !dir.is_dir() {
+            return Err(rejected(
+                "base_dir",
+                dir.display(),
+                format!("base_dir is not a directory: {}", dir.display()),
+            ));
+        }
+        self.values.base_dir = Some(std::fs::canonicalize(dir)?);
+        Ok(self)
+    }
     /// Specify the size of each device, as seen by the filesystem.
+    /// (`-b`/`--byte-count`)
+    ///
+    /// The size is in raw bytes. mkfs.btrfs' own size suffixes are base 2
+    /// (`1M` is 1024 * 1024 bytes), so:
+    ///
+    /// | mkfs.btrfs  | byte_count          |
+    /// |-------------|---------------------|
+    /// | `-b 512M`   | `536_870_912`       |
+    /// | `-b 1G`     | `1_073_741_824`     |
+    /// | `-b 1T`     | `1_099_511_627_776` |
+    ///
+    /// See [`byte_count_mib`](Self::byte_count_mib) to skip the arithmetic.
     ///
     /// # Example
     /// ```
@@ -161,9 +789,39 @@ impl FormatterOptions {
     /// # Ok::<(), Error>(())
     /// ```
     pub fn byte_count(mut self, byte_count: u64) -> Result<Self> {
+        if self.values.fill_device {
+            return Err(rejected(
+                "byte_count",
+                byte_count,
+                format!("byte_count ( = {byte_count} )\nMust not be set with fill_device"),
+            ));
+        }
         self.byte_count = Some(OsString::from(format!("--byte-count={byte_count}")));
+        self.values.byte_count = Some(byte_count);
         Ok(self)
     }
+    /// Specify the size of each device in MiB (1024 * 1024 bytes.)
+    ///
+    /// # Example
+    /// ```
+    /// # use mkfs_btrfs_rs::Error;
+    /// use mkfs_btrfs_rs::format::Formatter;
+    /// assert_eq!(
+    ///     Formatter::options().byte_count(536_870_912_u64)?,
+    ///     Formatter::options().byte_count_mib(512)?,
+    /// );
+    /// # Ok::<(), Error>(())
+    /// ```
+    pub fn byte_count_mib(self, mib: u64) -> Result<Self> {
+        let byte_count = mib.checked_mul(1024 * 1024).ok_or_else(|| {
+            rejected(
+                "byte_count",
+                mib,
+                format!("byte_count ( = {mib} MiB )\nMust be less than 2^64 bytes"),
+            )
+        })?;
+        self.byte_count(byte_count)
+    }
     /// Specify the checksum algorithm (as ChecksumAlgorithm.)
     ///
     /// # Example
@@ -179,6 +837,7 @@ impl FormatterOptions {
     /// ```
     pub fn checksum(mut self, checksum: ChecksumAlgorithm) -> Result<Self> {
         self.checksum = Some(OsString::from(format!("--checksum={checksum}")));
+        self.values.checksum = Some(checksum);
         Ok(self)
     }
     /// Specify the profile for data block groups (as DataProfile.)
@@ -193,149 +852,622 @@ impl FormatterOptions {
     /// ```
     pub fn data(mut self, data: DataProfile) -> Result<Self> {
         self.data = Some(OsString::from(format!("--data={data}")));
+        self.values.data = Some(data);
         Ok(self)
     }
     /// Set mkfs-time features. Unset features by prefixing them with '^'.
     ///
+    /// Accepts [`MkfsFeature`]s, or plain strings for features this crate
+    /// doesn't know about yet. Use [`verify_features`](Self::verify_features)
+    /// to check them against the installed mkfs.btrfs.
+    ///
     /// # Examples
     /// ```
     /// # use mkfs_btrfs_rs::Error;
-    /// use mkfs_btrfs_rs::format::Formatter;
+    /// use mkfs_btrfs_rs::format::{Formatter, MkfsFeature};
     /// Formatter::options()
-    ///     .features(["mixed-bg"])?;
+    ///     .features(["mixed-bg"])?
+    ///     .features([MkfsFeature::MixedBg, MkfsFeature::NoHoles.disable()])?;
     /// # Ok::<(), Error>(())
     /// ```
-    // TODO: Verify features.
-    // ? mkfs.btrfs verifies them again later, so is that even necessary?
-    pub fn features<'a>(mut self, features: impl IntoIterator<Item = &'a str>) -> Result<Self> {
+    pub fn features<T: std::fmt::Display>(
+        mut self,
+        features: impl IntoIterator<Item = T>,
+    ) -> Result<Self> {
+        let features = feature_list("features", features)?;
         self.features = Some(OsString::from(format!(
             "--features={}",
-            FormatOpt::List(
-                features
-                    .into_iter()
-                    .map(|x| -> String { x.to_owned() })
-                    .collect()
-            )
+            FormatOpt::List(features.clone())
         )));
+        self.values.features = features;
         Ok(self)
     }
-    /// Force-format the device, even if an existing filesystem is present.
+    /// Check that every feature set with [`features`](Self::features) is in `supported`,
+    /// such as the list from [`Formatter::supported_features`]
+    ///
+    /// mkfs.btrfs checks features itself, but only once it runs.
     ///
     /// # Examples
-    /// ```
+    /// ```no_run
     /// # use mkfs_btrfs_rs::Error;
     /// use mkfs_btrfs_rs::format::Formatter;
+    /// let supported = Formatter::supported_features()?;
     /// Formatter::options()
-    ///     .force()?;
+    ///     .features(["no-holes"])?
+    ///     .verify_features(&supported)?;
     /// # Ok::<(), Error>(())
     /// ```
-    pub fn force(mut self) -> Result<Self> {
-        self.force = Some(OsString::from("--force"));
+    pub fn verify_features<S: AsRef<str>>(self, supported: &[S]) -> Result<Self> {
+        let features = self.features.as_ref().and_then(arg_value).unwrap_or("");
+        for feature in features.split(',').filter(|feature| !feature.is_empty()) {
+            let name = feature.trim_start_matches('^');
+            if !supported.iter().any(|known| known.as_ref() == name) {
+                return Err(rejected(
+                    "features",
+                    feature,
+                    format!("features ( = \"{feature}\" )\nNot supported by this mkfs.btrfs"),
+                ));
+            }
+        }
         Ok(self)
     }
-    /// Set the partition label.
+    /// Enable a mkfs-time feature, but only if the installed mkfs.btrfs supports it
+    ///
+    /// Unsupported features are skipped silently, so provisioning code can use newer
+    /// features where they're available. Adds to any features already set.
     ///
     /// # Examples
-    /// ```
+    /// ```no_run
     /// # use mkfs_btrfs_rs::Error;
     /// use mkfs_btrfs_rs::format::Formatter;
     /// Formatter::options()
-    ///     .label("ExampleLabel")?;
+    ///     .enable_if_supported("block-group-tree")?;
     /// # Ok::<(), Error>(())
     /// ```
-    pub fn label(mut self, label: &str) -> Result<Self> {
-        if label.len() > 255 {
-            return Err(ArgumentError(format!(
-                "label cannot be longer than 255 bytes: {}, {label}",
-                label.len()
-            )));
+    pub fn enable_if_supported(self, feature: &str) -> Result<Self> {
+        self.enable_if_supported_by(feature, DEFAULT_BINARY)
+    }
+    /// Enable a mkfs-time feature, but only if the mkfs.btrfs at `binary` supports it
+    ///
+    /// See [`enable_if_supported`](Self::enable_if_supported).
+    pub fn enable_if_supported_by<P: AsRef<Path>>(self, feature: &str, binary: P) -> Result<Self> {
+        if !supported_features_of(binary.as_ref())?
+            .iter()
+            .any(|supported| supported == feature)
+        {
+            return Ok(self);
         }
-        self.label = Some(OsString::from(format!("--label={label}")));
-        Ok(self)
+        self.add_features([feature])
     }
-    /// Specify the profile for metadata block groups (as DataProfile.)
+    /// Add mkfs-time features to those already set, instead of replacing them like
+    /// [`features`](Self::features) does
+    ///
+    /// Features already in the list aren't repeated. Adding `^feature` drops `feature`,
+    /// and the other way around, so the last call wins.
     ///
     /// # Examples
     /// ```
     /// # use mkfs_btrfs_rs::Error;
-    /// use mkfs_btrfs_rs::format::{DataProfile, Formatter};
-    /// Formatter::options()
-    ///     .metadata(DataProfile::Dup)?;
+    /// use mkfs_btrfs_rs::format::{Formatter, MkfsFeature};
+    /// let formatter = Formatter::options()
+    ///     .add_features([MkfsFeature::NoHoles])?
+    ///     .add_features([MkfsFeature::Quota, MkfsFeature::NoHoles])?
+    ///     .build();
+    /// assert!(formatter.iter_args().any(|arg| arg == "--features=no-holes,quota"));
     /// # Ok::<(), Error>(())
     /// ```
-    pub fn metadata(mut self, metadata: DataProfile) -> Result<Self> {
-        self.metadata = Some(OsString::from(format!("--metadata={metadata}")));
-        Ok(self)
+    pub fn add_features<T: std::fmt::Display>(
+        self,
+        features: impl IntoIterator<Item = T>,
+    ) -> Result<Self> {
+        let mut all = self.values.features.clone();
+        for feature in feature_list("features", features)? {
+            if all.contains(&feature) {
+                continue;
+            }
+            let name = feature.trim_start_matches('^');
+            all.retain(|set| set.trim_start_matches('^') != name);
+            all.push(feature);
+        }
+        self.features(all)
     }
-    /// Enable mixing of data and metadata blocks
+    /// Disable mkfs-time features, merged with those already set
+    ///
+    /// Each name is prefixed with `^` for mkfs.btrfs, so pass them without it.
+    /// Disabling a feature that is already enabled is rejected, rather than
+    /// silently dropping it like [`add_features`](Self::add_features) does.
     ///
     /// # Examples
     /// ```
     /// # use mkfs_btrfs_rs::Error;
-    /// use mkfs_btrfs_rs::format::Formatter;
-    /// Formatter::options()
-    ///     .mixed()?;
+    /// use mkfs_btrfs_rs::format::{Formatter, MkfsFeature};
+    /// let formatter = Formatter::options()
+    ///     .add_features([MkfsFeature::Quota])?
+    ///     .disable_features(["no-holes"])?
+    ///     .build();
+    /// assert!(formatter.iter_args().any(|arg| arg == "--features=quota,^no-holes"));
     /// # Ok::<(), Error>(())
     /// ```
-    pub fn mixed(mut self) -> Result<Self> {
-        self.mixed = Some(OsString::from("--mixed"));
-        Ok(self)
+    pub fn disable_features<'a>(self, features: impl IntoIterator<Item = &'a str>) -> Result<Self> {
+        let mut disabled = Vec::new();
+        for feature in feature_list("features", features)? {
+            if feature.is_empty() || feature.starts_with('^') {
+                return Err(rejected(
+                    "features",
+                    &feature,
+                    format!("features ( = \"{feature}\" )\nMust be a feature name, without '^'"),
+                ));
+            }
+            if self.values.features.contains(&feature) {
+                return Err(rejected(
+                    "features",
+                    &feature,
+                    format!("features ( = \"{feature}\" )\nMust not be both enabled and disabled"),
+                ));
+            }
+            disabled.push(format!("^{feature}"));
+        }
+        self.add_features(disabled)
     }
-    /// Disable implicit TRIM of storage device.
+    /// Force-format the device, even if an existing filesystem is present.
     ///
     /// # Examples
     /// ```
     /// # use mkfs_btrfs_rs::Error;
     /// use mkfs_btrfs_rs::format::Formatter;
     /// Formatter::options()
-    ///     .no_discard()?;
+    ///     .force()?;
     /// # Ok::<(), Error>(())
     /// ```
-    pub fn no_discard(mut self) -> Result<Self> {
-        self.no_discard = Some(OsString::from("--nodiscard"));
+    pub fn force(mut self) -> Result<Self> {
+        self.force = Some(OsString::from("--force"));
         Ok(self)
     }
-    /// Specify the size of a b-tree node
+    /// Set the partition label.
+    ///
+    /// The label can be at most 255 bytes of UTF-8, which is fewer than 255 characters
+    /// if any of them are multibyte: 64 emoji of 4 bytes each are already too long.
     ///
-    /// `nodesize must be a power of 2 less than 2^14
+    /// An empty label is rejected, since it's more likely a missing value than
+    /// a deliberate choice; call [`allow_empty_label`](Self::allow_empty_label) first
+    /// to pass it on to mkfs.btrfs anyway.
     ///
     /// # Examples
     /// ```
     /// # use mkfs_btrfs_rs::Error;
     /// use mkfs_btrfs_rs::format::Formatter;
     /// Formatter::options()
-    ///     .label("ExampleLabel")?;
+    ///     .label("ExampleLabel")?
+    ///     .label(format!("backup-{}", 7))?;
     /// # Ok::<(), Error>(())
     /// ```
-    pub fn nodesize(mut self, nodesize: usize) -> Result<Self> {
-        if nodesize.is_power_of_two() && nodesize <= 16384 {
-            self.nodesize = Some(OsString::from(format!("--nodesize={nodesize}")));
-            Ok(self)
-        } else {
-            Err(ArgumentError(format!(
-                "node_size ( = {nodesize} )\nMust be a power of 2, and <= 16384"
-            )))
+    pub fn label<S: AsRef<str>>(mut self, label: S) -> Result<Self> {
+        let label = label.as_ref();
+        if label.is_empty() && !self.values.allow_empty_label {
+            return Err(rejected(
+                "label",
+                label,
+                String::from(
+                    "label ( = \"\" )\nMust not be empty, unless allow_empty_label() is set",
+                ),
+            ));
+        }
+        if label.len() > 255 {
+            return Err(rejected(
+                "label",
+                label,
+                format!(
+                    "label ( = \"{label}\" )\nMust be at most 255 bytes (UTF-8), not {}",
+                    label.len()
+                ),
+            ));
         }
+        self.label = Some(OsString::from(format!("--label={label}")));
+        Ok(self)
     }
-    /// Specify a directory containing data to copy into the btrfs filesystem.
+    /// Let [`label`](Self::label) accept an empty label
+    ///
+    /// mkfs.btrfs is then passed `--label=`, which leaves the filesystem unlabelled.
+    /// Must be called before `label`.
     ///
     /// # Examples
     /// ```
     /// # use mkfs_btrfs_rs::Error;
     /// use mkfs_btrfs_rs::format::Formatter;
+    /// assert!(Formatter::options().label("").is_err());
     /// Formatter::options()
-    ///     .rootdir("./testdir")?;
+    ///     .allow_empty_label()?
+    ///     .label("")?;
     /// # Ok::<(), Error>(())
     /// ```
-    pub fn rootdir<P: AsRef<Path>>(mut self, rootdir: P) -> Result<Self> {
-        // make sure the rootdir is a valid Path
-        rootdir.as_ref().try_exists()?;
-        let rootdir = format!("--rootdir={}", rootdir.as_ref().display());
-        self.rootdir = Some(OsString::from(rootdir));
+    pub fn allow_empty_label(mut self) -> Result<Self> {
+        self.values.allow_empty_label = true;
         Ok(self)
     }
-    /// Set runtime features.
-    /// Unset features by prefixing them with '^'.
+    /// Set the partition label to `prefix`, followed by 8 random hex digits.
+    ///
+    /// Keeps labels unique when formatting many images. Fails if `prefix` leaves
+    /// no room for the suffix in the 255-byte limit.
+    ///
+    /// # Examples
+    /// ```
+    /// # use mkfs_btrfs_rs::Error;
+    /// use mkfs_btrfs_rs::format::Formatter;
+    /// Formatter::options()
+    ///     .random_label("test-farm-")?;
+    /// # Ok::<(), Error>(())
+    /// ```
+    pub fn random_label(self, prefix: &str) -> Result<Self> {
+        if prefix.len() > 255 - 8 {
+            return Err(rejected(
+                "label",
+                prefix,
+                format!(
+                    "label prefix ( = \"{prefix}\" )\nMust be at most 247 bytes, is {}",
+                    prefix.len()
+                ),
+            ));
+        }
+        let suffix = random::random_u64() as u32;
+        self.label(format!("{prefix}{suffix:08x}"))
+    }
+    /// Specify the profile for metadata block groups (as DataProfile.)
+    ///
+    /// # Examples
+    /// ```
+    /// # use mkfs_btrfs_rs::Error;
+    /// use mkfs_btrfs_rs::format::{DataProfile, Formatter};
+    /// Formatter::options()
+    ///     .metadata(DataProfile::Dup)?;
+    /// # Ok::<(), Error>(())
+    /// ```
+    pub fn metadata(mut self, metadata: DataProfile) -> Result<Self> {
+        self.metadata = Some(OsString::from(format!("--metadata={metadata}")));
+        self.values.metadata = Some(metadata);
+        Ok(self)
+    }
+    /// Keep two copies of metadata, even on a single device.
+    ///
+    /// Shorthand for `.metadata(DataProfile::Dup)`. For copies on separate
+    /// devices, use [`DataProfile::Raid1`] (2 copies), [`DataProfile::Raid1c3`] (3 copies),
+    /// or [`DataProfile::Raid1c4`] (4 copies) instead.
+    ///
+    /// # Examples
+    /// ```
+    /// # use mkfs_btrfs_rs::Error;
+    /// use mkfs_btrfs_rs::format::Formatter;
+    /// Formatter::options()
+    ///     .metadata_redundant()?;
+    /// # Ok::<(), Error>(())
+    /// ```
+    pub fn metadata_redundant(self) -> Result<Self> {
+        self.metadata(DataProfile::Dup)
+    }
+    /// Enable mixing of data and metadata blocks
+    ///
+    /// # Examples
+    /// ```
+    /// # use mkfs_btrfs_rs::Error;
+    /// use mkfs_btrfs_rs::format::Formatter;
+    /// Formatter::options()
+    ///     .mixed()?;
+    /// # Ok::<(), Error>(())
+    /// ```
+    pub fn mixed(mut self) -> Result<Self> {
+        self.mixed = Some(OsString::from("--mixed"));
+        Ok(self)
+    }
+    /// Disable implicit TRIM of storage device.
+    ///
+    /// # Examples
+    /// ```
+    /// # use mkfs_btrfs_rs::Error;
+    /// use mkfs_btrfs_rs::format::Formatter;
+    /// Formatter::options()
+    ///     .no_discard()?;
+    /// # Ok::<(), Error>(())
+    /// ```
+    pub fn no_discard(mut self) -> Result<Self> {
+        self.no_discard = Some(OsString::from("--nodiscard"));
+        Ok(self)
+    }
+    /// Decide on discard (TRIM) when formatting, by whether the device spins
+    ///
+    /// Rotational block devices get `--nodiscard`, since they can't use it; SSDs are
+    /// left to discard as usual. Files, and devices whose kind can't be read from sysfs,
+    /// are left alone. Linux only.
+    ///
+    /// # Examples
+    /// ```
+    /// # use mkfs_btrfs_rs::Error;
+    /// use mkfs_btrfs_rs::format::Formatter;
+    /// Formatter::options()
+    ///     .auto_discard()?;
+    /// # Ok::<(), Error>(())
+    /// ```
+    pub fn auto_discard(mut self) -> Result<Self> {
+        self.values.auto_discard = true;
+        Ok(self)
+    }
+    /// Specify the size of a b-tree node
+    ///
+    /// `nodesize` must be a power of 2, no larger than [`MAX_NODESIZE`]
+    ///
+    /// # Examples
+    /// ```
+    /// # use mkfs_btrfs_rs::Error;
+    /// use mkfs_btrfs_rs::format::Formatter;
+    /// Formatter::options()
+    ///     .nodesize(16384_usize)?;
+    /// # Ok::<(), Error>(())
+    /// ```
+    pub fn nodesize(mut self, nodesize: usize) -> Result<Self> {
+        let nodesize = validate_block_size("nodesize", nodesize, MAX_NODESIZE)?;
+        self.nodesize = Some(OsString::from(format!("--nodesize={nodesize}")));
+        self.values.nodesize = Some(nodesize);
+        Ok(self)
+    }
+    /// Specify a directory containing data to copy into the btrfs filesystem.
+    ///
+    /// The path is canonicalized, so relative paths become absolute (against the
+    /// [`base_dir`](Self::base_dir), if set) and symlinks are resolved. It must exist.
+    ///
+    /// mkfs.btrfs always copies the ownership and permissions of the files along
+    /// with their contents; there's no option to leave them out.
+    ///
+    /// # Examples
+    /// ```
+    /// # use mkfs_btrfs_rs::Error;
+    /// use mkfs_btrfs_rs::format::Formatter;
+    /// Formatter::options()
+    ///     .rootdir("./src")?;
+    /// # Ok::<(), Error>(())
+    /// ```
+    pub fn rootdir<P: AsRef<Path>>(mut self, rootdir: P) -> Result<Self> {
+        let rootdir = self.values.resolve(rootdir.as_ref()).into_owned();
+        if !rootdir.try_exists()? {
+            return Err(rejected(
+                "rootdir",
+                rootdir.display(),
+                format!("rootdir does not exist: {}", rootdir.display()),
+            ));
+        }
+        let rootdir = format!("--rootdir={}", std::fs::canonicalize(rootdir)?.display());
+        self.rootdir = Some(OsString::from(rootdir));
+        Ok(self)
+    }
+    /// Extract a tar archive into a temp directory, and use that as the rootdir
+    ///
+    /// Enabled by the `tar` feature, and runs the system `tar`, so any compression
+    /// it recognizes works. The directory is deleted once these options, and every
+    /// Formatter built from them, are dropped.
+    ///
+    /// # Examples
+    /// ```no_run
+    /// # use mkfs_btrfs_rs::Error;
+    /// use mkfs_btrfs_rs::format::Formatter;
+    /// Formatter::options()
+    ///     .rootdir_from_tar("./rootfs.tar.gz")?
+    ///     .shrink()?
+    ///     .build()
+    ///     .format("./test.btrfs")?;
+    /// # Ok::<(), Error>(())
+    /// ```
+    #[cfg(feature = "tar")]
+    pub fn rootdir_from_tar<P: AsRef<Path>>(mut self, tar_path: P) -> Result<Self> {
+        let tar_path = self.values.resolve(tar_path.as_ref()).into_owned();
+        let dir = crate::temp::TempDir::new()?;
+        let output = Command::new("tar")
+            .arg("-xf")
+            .arg(&tar_path)
+            .arg("-C")
+            .arg(dir.path())
+            .stdin(Stdio::null())
+            .output()?;
+        if !output.status.success() {
+            return Err(rejected(
+                "rootdir",
+                tar_path.display(),
+                format!(
+                    "could not extract {}: {}",
+                    tar_path.display(),
+                    output.stderr_str().trim_end()
+                ),
+            ));
+        }
+        self = self.rootdir(dir.path())?;
+        self.values.staged = Some(Arc::new(dir));
+        Ok(self)
+    }
+    /// Copy several directories into one temp directory, and use that as the rootdir
+    ///
+    /// Enabled by the `tempfile` feature. Directories are merged, so only files
+    /// (and symlinks) at the same path conflict, and `policy` decides which one is kept.
+    /// Permissions are copied, but ownership isn't. Symlinks are only copied on unix;
+    /// elsewhere they fail with [`UnsupportedPlatform`]. The directory is deleted once
+    /// these options, and every Formatter built from them, are dropped.
+    ///
+    /// # Examples
+    /// ```no_run
+    /// # use mkfs_btrfs_rs::Error;
+    /// use mkfs_btrfs_rs::format::{ConflictPolicy, Formatter};
+    /// Formatter::options()
+    ///     .rootdirs(["./base", "./overlay"], ConflictPolicy::LastWins)?;
+    /// # Ok::<(), Error>(())
+    /// ```
+    #[cfg(feature = "tempfile")]
+    pub fn rootdirs<P: AsRef<Path>>(
+        mut self,
+        dirs: impl IntoIterator<Item = P>,
+        policy: ConflictPolicy,
+    ) -> Result<Self> {
+        let dirs: Vec<PathBuf> = dirs
+            .into_iter()
+            .map(|dir| self.values.resolve(dir.as_ref()).into_owned())
+            .collect();
+        if dirs.is_empty() {
+            return Err(rejected(
+                "rootdirs",
+                "[]",
+                String::from("rootdirs ( = [] )\nMust list at least one directory"),
+            ));
+        }
+        if let Some(missing) = dirs.iter().find(|dir| !dir.is_dir()) {
+            return Err(rejected(
+                "rootdirs",
+                missing.display(),
+                format!("rootdir is not a directory: {}", missing.display()),
+            ));
+        }
+        let staging = crate::temp::TempDir::new()?;
+        for dir in &dirs {
+            merge_dir(dir, staging.path(), policy)?;
+        }
+        self = self.rootdir(staging.path())?;
+        self.values.staged = Some(Arc::new(staging));
+        Ok(self)
+    }
+    /// Specify a rootdir, and set `byte_count` to fit its contents,
+    /// plus [`DEFAULT_ROOTDIR_OVERHEAD`] percent for metadata.
+    ///
+    /// # Examples
+    /// ```no_run
+    /// # use mkfs_btrfs_rs::Error;
+    /// use mkfs_btrfs_rs::format::Formatter;
+    /// Formatter::options()
+    ///     .rootdir_sized("./src")?;
+    /// # Ok::<(), Error>(())
+    /// ```
+    pub fn rootdir_sized<P: AsRef<Path>>(self, rootdir: P) -> Result<Self> {
+        self.rootdir_sized_with_overhead(rootdir, DEFAULT_ROOTDIR_OVERHEAD)
+    }
+    /// Specify a rootdir, and set `byte_count` to fit its contents,
+    /// plus `overhead` percent for metadata.
+    ///
+    /// The `byte_count` is never less than [`MIN_ROOTDIR_BYTE_COUNT`].
+    ///
+    /// # Examples
+    /// ```no_run
+    /// # use mkfs_btrfs_rs::Error;
+    /// use mkfs_btrfs_rs::format::Formatter;
+    /// Formatter::options()
+    ///     .rootdir_sized_with_overhead("./src", 25)?;
+    /// # Ok::<(), Error>(())
+    /// ```
+    pub fn rootdir_sized_with_overhead<P: AsRef<Path>>(
+        self,
+        rootdir: P,
+        overhead: u64,
+    ) -> Result<Self> {
+        let content = dir_size(&self.values.resolve(rootdir.as_ref()))?;
+        let byte_count = content
+            .checked_mul(overhead)
+            .map(|extra| extra / 100)
+            .and_then(|extra| content.checked_add(extra))
+            .ok_or_else(|| {
+                rejected(
+                    "rootdir",
+                    overhead,
+                    format!(
+                        "rootdir ( = {content} bytes ), overhead ( = {overhead}% )\n\
+                         Too large to fit in a byte_count"
+                    ),
+                )
+            })?;
+        self.rootdir(rootdir)?
+            .byte_count(byte_count.max(MIN_ROOTDIR_BYTE_COUNT))
+    }
+    /// Specify a rootdir, and set `byte_count` to its content size times `1.0 + slack`.
+    ///
+    /// `slack` must be finite and not negative; `0.5` leaves half the content size free.
+    /// The `byte_count` is never less than [`MIN_ROOTDIR_BYTE_COUNT`].
+    ///
+    /// # Examples
+    /// ```no_run
+    /// # use mkfs_btrfs_rs::Error;
+    /// use mkfs_btrfs_rs::format::Formatter;
+    /// Formatter::options()
+    ///     .rootdir_with_slack("./src", 0.5)?;
+    /// # Ok::<(), Error>(())
+    /// ```
+    pub fn rootdir_with_slack<P: AsRef<Path>>(self, rootdir: P, slack: f64) -> Result<Self> {
+        if !slack.is_finite() || slack < 0.0 {
+            return Err(rejected(
+                "rootdir",
+                slack,
+                format!("slack ( = {slack} )\nMust be finite, and not negative"),
+            ));
+        }
+        let content = dir_size(&self.values.resolve(rootdir.as_ref()))?;
+        let byte_count = (content as f64 * (1.0 + slack)).ceil();
+        // u64::MAX isn't exactly representable; it rounds up to 2^64
+        if byte_count >= u64::MAX as f64 {
+            return Err(rejected(
+                "rootdir",
+                slack,
+                format!(
+                    "rootdir ( = {content} bytes ), slack ( = {slack} )\n\
+                     Too large to fit in a byte_count"
+                ),
+            ));
+        }
+        self.rootdir(rootdir)?
+            .byte_count((byte_count as u64).max(MIN_ROOTDIR_BYTE_COUNT))
+    }
+    /// Apply a [`Preset`], replacing any of the options it sets
+    ///
+    /// See [`Preset`] for exactly which options each one sets.
+    ///
+    /// # Examples
+    /// ```
+    /// # use mkfs_btrfs_rs::Error;
+    /// use mkfs_btrfs_rs::format::{Formatter, Preset};
+    /// Formatter::options()
+    ///     .preset(Preset::Server)?
+    ///     .label("storage")?;
+    /// # Ok::<(), Error>(())
+    /// ```
+    pub fn preset(self, preset: Preset) -> Result<Self> {
+        use MkfsFeature::*;
+        match preset {
+            Preset::Minimal => self
+                .mixed()?
+                .profile(DataProfile::Single)?
+                .nodesize(4096)?
+                .sectorsize(4096),
+            Preset::Server => self
+                .checksum(ChecksumAlgorithm::XxHash)?
+                .features([NoHoles, FreeSpaceTree, BlockGroupTree])?
+                .nodesize(16384)?
+                .metadata(DataProfile::Dup),
+            Preset::SsdOptimized => self
+                .checksum(ChecksumAlgorithm::Crc32c)?
+                .features([NoHoles, FreeSpaceTree])?
+                .nodesize(16384),
+            Preset::Compatibility => self
+                .checksum(ChecksumAlgorithm::Crc32c)?
+                .features([NoHoles.disable(), FreeSpaceTree.disable()])?
+                .nodesize(16384)?
+                .no_discard(),
+        }
+    }
+    /// Specify the same profile for both data and metadata block groups (as DataProfile.)
+    ///
+    /// Equivalent to calling both [`data`](Self::data) and [`metadata`](Self::metadata).
+    ///
+    /// # Examples
+    /// ```
+    /// # use mkfs_btrfs_rs::Error;
+    /// use mkfs_btrfs_rs::format::{DataProfile, Formatter};
+    /// Formatter::options()
+    ///     .profile(DataProfile::Raid1)?;
+    /// # Ok::<(), Error>(())
+    /// ```
+    pub fn profile(self, profile: DataProfile) -> Result<Self> {
+        self.data(profile)?.metadata(profile)
+    }
+    /// Set runtime features.
+    /// Unset features by prefixing them with '^'.
     ///
     /// # Examples
     /// ```
@@ -350,19 +1482,17 @@ impl FormatterOptions {
         mut self,
         features: impl IntoIterator<Item = &'a str>,
     ) -> Result<Self> {
+        let features = feature_list("runtime_features", features)?;
         self.runtime_features = Some(OsString::from(format!(
             "--runtime-features={}",
-            FormatOpt::List(
-                features
-                    .into_iter()
-                    .map(|x| -> String { x.to_owned() })
-                    .collect(),
-            )
+            FormatOpt::List(features)
         )));
         Ok(self)
     }
     /// Set sector size.
     ///
+    /// `sectorsize` must be a power of 2, no larger than [`MAX_SECTORSIZE`]
+    ///
     /// *If set to a value unsupported by the current kernel,*
     /// *the resulting volume will not be mountable.*
     ///
@@ -375,7 +1505,66 @@ impl FormatterOptions {
     /// # Ok::<(), Error>(())
     /// ```
     pub fn sectorsize(mut self, sectorsize: usize) -> Result<Self> {
+        let sectorsize = validate_block_size("sectorsize", sectorsize, MAX_SECTORSIZE)?;
         self.sectorsize = Some(OsString::from(format!("--sectorsize={sectorsize}")));
+        self.values.sectorsize = Some(sectorsize);
+        Ok(self)
+    }
+    /// Choose what happens when a 32-byte checksum (sha256 or blake2) is paired
+    /// with a 4096-byte `sectorsize`
+    ///
+    /// Big checksums on small sectors take up more metadata, and every read has to
+    /// hash more often, which can be slow. Defaults to [`Strictness::Warn`].
+    ///
+    /// # Examples
+    /// ```
+    /// # use mkfs_btrfs_rs::Error;
+    /// use mkfs_btrfs_rs::format::{ChecksumAlgorithm, Formatter, Strictness};
+    /// let options = Formatter::options()
+    ///     .checksum(ChecksumAlgorithm::Blake2)?
+    ///     .sectorsize(4096)?
+    ///     .checksum_check(Strictness::Deny)?;
+    /// assert!(options.validate().is_err());
+    /// # Ok::<(), Error>(())
+    /// ```
+    pub fn checksum_check(mut self, check: Strictness) -> Result<Self> {
+        self.values.checksum_check = check;
+        Ok(self)
+    }
+    /// Choose what happens when `sectorsize` isn't this system's page size
+    ///
+    /// Linux only mounts btrfs filesystems whose sector size the running kernel supports,
+    /// which is usually just its page size. Defaults to [`Strictness::Warn`].
+    ///
+    /// # Examples
+    /// ```
+    /// # use mkfs_btrfs_rs::Error;
+    /// use mkfs_btrfs_rs::format::{Formatter, Strictness};
+    /// Formatter::options()
+    ///     .sectorsize(4096_usize)?
+    ///     .page_size_check(Strictness::Deny)?;
+    /// # Ok::<(), Error>(())
+    /// ```
+    pub fn page_size_check(mut self, check: Strictness) -> Result<Self> {
+        self.values.page_size_check = check;
+        Ok(self)
+    }
+    /// Choose what happens when `shrink` is set without a `rootdir`, which mkfs.btrfs ignores
+    ///
+    /// Defaults to [`Strictness::Deny`].
+    ///
+    /// # Examples
+    /// ```
+    /// # use mkfs_btrfs_rs::Error;
+    /// use mkfs_btrfs_rs::format::{Formatter, Strictness};
+    /// Formatter::options()
+    ///     .shrink()?
+    ///     .shrink_check(Strictness::Warn)?
+    ///     .try_build()?;
+    /// # Ok::<(), Error>(())
+    /// ```
+    pub fn shrink_check(mut self, check: Strictness) -> Result<Self> {
+        self.values.shrink_check = Some(check);
         Ok(self)
     }
     /// If the specified device is a file, and the `rootdir` option is specified,
@@ -395,6 +1584,9 @@ impl FormatterOptions {
     }
     /// Set the partition UUID
     ///
+    /// The UUID must be in the hyphenated `8-4-4-4-12` hex format,
+    /// and must not be the nil UUID, which btrfs reserves.
+    ///
     /// # Examples
     /// ```
     /// # use mkfs_btrfs_rs::Error;
@@ -403,109 +1595,2389 @@ impl FormatterOptions {
     ///     .uuid("73e1b7e2-a3a8-49c2-b258-06f01a889bba")?;
     /// # Ok::<(), Error>(())
     /// ```
-    // TODO: Verify UUIDs (with external crate?)
     pub fn uuid(mut self, uuid: &str) -> Result<Self> {
+        validate_uuid("uuid", uuid)?;
         self.uuid = Some(OsString::from(format!("--uuid={uuid}")));
         Ok(self)
     }
 
-    /// Convert self into args (AKA `Vec<OsString>`)
-    fn to_args(&self) -> Vec<OsString> {
-        let mut args = vec![];
-        for option in [
-            &self.byte_count,
-            &self.checksum,
-            &self.data,
-            &self.features,
-            &self.force,
-            &self.label,
-            &self.metadata,
-            &self.mixed,
-            &self.no_discard,
-            &self.nodesize,
-            &self.rootdir,
-            &self.runtime_features,
-            &self.sectorsize,
-            &self.shrink,
-            &self.uuid,
-        ] {
-            if let Some(arg) = option.as_ref() {
-                args.push(arg.clone());
-            }
-        }
-        args
-    }
-
-    /// Dump FormatterOptions as they'll be passed to mkfs.btrfs
+    /// Set the UUID of the device, as opposed to the whole filesystem (`--device-uuid`)
+    ///
+    /// Checked the same way as [`uuid`](Self::uuid). It must also differ from the filesystem
+    /// UUID, which is checked by [`try_build`](Self::try_build), and
+    /// [`Formatter::format_batch`] won't give it to more than one device.
     ///
     /// # Examples
     /// ```
+    /// # use mkfs_btrfs_rs::Error;
     /// use mkfs_btrfs_rs::format::Formatter;
     /// Formatter::options()
-    ///     .dump_args();
+    ///     .device_uuid("2b0b4b8e-4d4b-4f5b-9a53-396e3145e1fa")?;
+    /// # Ok::<(), Error>(())
     /// ```
-    pub fn dump_args(self) -> Self {
-        println!("{:#?}", self.to_args());
-        self
+    pub fn device_uuid(mut self, uuid: &str) -> Result<Self> {
+        validate_uuid("device_uuid", uuid)?;
+        self.device_uuid = Some(OsString::from(format!("--device-uuid={uuid}")));
+        self.values.device_uuid = Some(uuid.to_owned());
+        Ok(self)
     }
-
-    /// Bake FormatterOptions into a Formatter
+    /// Derive both the filesystem and device UUIDs from `seed`
+    ///
+    /// The same seed always gives the same UUIDs, which keeps golden images reproducible.
     ///
     /// # Examples
     /// ```
     /// # use mkfs_btrfs_rs::Error;
     /// use mkfs_btrfs_rs::format::Formatter;
-    /// Formatter::options()
-    ///     .label("my-Btrfs-volume")?
-    ///     .rootdir("./testdir")?
-    ///     .shrink()?
-    ///     .build();
+    /// let options = || Formatter::options().uuids_from_seed(42);
+    /// assert_eq!(options()?, options()?);
     /// # Ok::<(), Error>(())
     /// ```
-    pub fn build(&self) -> Formatter {
-        let args = self.to_args();
-        Formatter { args }
+    pub fn uuids_from_seed(self, seed: u64) -> Result<Self> {
+        let mut rng = random::SplitMix64::new(seed);
+        let uuid = random::uuid_v4_from(rng.next_u64(), rng.next_u64());
+        let device_uuid = random::uuid_v4_from(rng.next_u64(), rng.next_u64());
+        self.uuid(&uuid)?.device_uuid(&device_uuid)
+    }
+    /// Generate a random UUID for the filesystem.
+    ///
+    /// Without this (or [`uuid`](Self::uuid)), mkfs.btrfs picks a random UUID itself,
+    /// but you won't know what it is until it's done. This generates one up front,
+    /// so it can be used elsewhere before formatting.
+    ///
+    /// # Examples
+    /// ```
+    /// # use mkfs_btrfs_rs::Error;
+    /// use mkfs_btrfs_rs::format::Formatter;
+    /// Formatter::options()
+    ///     .uuid_random()?;
+    /// # Ok::<(), Error>(())
+    /// ```
+    pub fn uuid_random(self) -> Result<Self> {
+        self.uuid_mode(UuidMode::Random)
+    }
+    /// Generate a UUID for the filesystem, in one of the ways in [`UuidMode`].
+    ///
+    /// # Examples
+    /// ```
+    /// # use mkfs_btrfs_rs::Error;
+    /// use mkfs_btrfs_rs::format::{Formatter, UuidMode};
+    /// Formatter::options()
+    ///     .uuid_mode(UuidMode::Time)?;
+    /// # Ok::<(), Error>(())
+    /// ```
+    pub fn uuid_mode(self, mode: UuidMode) -> Result<Self> {
+        let uuid = match mode {
+            UuidMode::Random => random::uuid_v4(),
+            UuidMode::Time => random::uuid_v1(),
+        };
+        self.uuid(&uuid)
     }
-}
 
-/// Formats anything that can be Btrfs-formatted.
-#[derive(Debug, Hash, Eq, PartialEq, Ord, PartialOrd)]
-pub struct Formatter {
-    args: Vec<OsString>,
-}
+    /// Convert self into args (AKA `Vec<OsString>`)
+    pub(crate) fn to_args(&self) -> Vec<OsString> {
+        let (features, runtime_features) = if self.values.deterministic {
+            (
+                self.features.as_ref().map(sorted_list),
+                self.runtime_features.as_ref().map(sorted_list),
+            )
+        } else {
+            (self.features.clone(), self.runtime_features.clone())
+        };
+        let mut args = vec![];
+        for option in [
+            &self.byte_count,
+            &self.checksum,
+            &self.data,
+            &self.device_uuid,
+            &features,
+            &self.force,
+            &self.label,
+            &self.metadata,
+            &self.mixed,
+            &self.no_discard,
+            &self.nodesize,
+            &self.rootdir,
+            &runtime_features,
+            &self.sectorsize,
+            &self.shrink,
+            &self.uuid,
+        ] {
+            if let Some(arg) = option.as_ref() {
+                args.push(arg.clone());
+            }
+        }
+        args.extend(self.extra_args.iter().cloned());
+        args
+    }
 
-impl Formatter {
-    /// Specify FormatterOptions first, then build a formatter
+    /// Sort `features` and `runtime_features`, so the same set of features
+    /// always produces the same args, whatever order they were given in.
     ///
+    /// # Examples
     /// ```
     /// # use mkfs_btrfs_rs::Error;
     /// use mkfs_btrfs_rs::format::Formatter;
+    /// use std::collections::HashSet;
+    /// let features = HashSet::from(["no-holes", "mixed-bg"]);
+    /// Formatter::options()
+    ///     .features(features)?
+    ///     .deterministic()?;
+    /// # Ok::<(), Error>(())
+    /// ```
+    pub fn deterministic(mut self) -> Result<Self> {
+        self.values.deterministic = true;
+        Ok(self)
+    }
+
+    /// Get the profile set for data block groups, if any
+    ///
+    /// # Examples
+    /// ```
+    /// # use mkfs_btrfs_rs::Error;
+    /// use mkfs_btrfs_rs::format::{DataProfile, Formatter};
     /// let options = Formatter::options()
-    /// /* set options here...*/;
-    /// options.build().format("./test.btrfs")?;
+    ///     .data(DataProfile::Raid1)?;
+    /// assert_eq!(Some(DataProfile::Raid1), options.data_profile());
     /// # Ok::<(), Error>(())
     /// ```
-    pub fn options() -> FormatterOptions {
-        FormatterOptions::default()
+    pub fn data_profile(&self) -> Option<DataProfile> {
+        self.values.data
     }
-    /// Format a device with mkfs.btrfs
+    /// Get the profile set for metadata block groups, if any
     ///
     /// # Examples
     /// ```
     /// # use mkfs_btrfs_rs::Error;
-    /// use mkfs_btrfs_rs::format::*;
+    /// use mkfs_btrfs_rs::format::{DataProfile, Formatter};
+    /// let options = Formatter::options()
+    ///     .metadata(DataProfile::Dup)?;
+    /// assert_eq!(Some(DataProfile::Dup), options.metadata_profile());
+    /// # Ok::<(), Error>(())
+    /// ```
+    pub fn metadata_profile(&self) -> Option<DataProfile> {
+        self.values.metadata
+    }
+    /// Check whether an option has been set
+    ///
+    /// # Examples
+    /// ```
+    /// # use mkfs_btrfs_rs::Error;
+    /// use mkfs_btrfs_rs::format::{Field, Formatter};
+    /// let options = Formatter::options()
+    ///     .label("ExampleLabel")?;
+    /// assert!(options.is_set(Field::Label));
+    /// assert!(!options.is_set(Field::Uuid));
+    /// # Ok::<(), Error>(())
+    /// ```
+    pub fn is_set(&self, field: Field) -> bool {
+        self.arg(field).is_some()
+    }
+
+    /// List the options that differ from `other`, with their rendered args in each
+    ///
+    /// `self` is the old config, and `other` the new one. Only options with a [`Field`]
+    /// are compared, not [`extra_args`](Self::extra_args).
+    ///
+    /// # Examples
+    /// ```
+    /// # use mkfs_btrfs_rs::Error;
+    /// use mkfs_btrfs_rs::format::{Field, Formatter};
+    /// let old = Formatter::options().label("before")?;
+    /// let new = old.clone().label("after")?;
+    /// let changes = old.diff(&new);
+    /// assert_eq!(1, changes.len());
+    /// assert_eq!(Field::Label, changes[0].field);
+    /// # Ok::<(), Error>(())
+    /// ```
+    pub fn diff(&self, other: &FormatterOptions) -> Vec<FieldChange> {
+        Field::ALL
+            .into_iter()
+            .filter(|&field| self.arg(field) != other.arg(field))
+            .map(|field| FieldChange {
+                field,
+                old: self.arg(field).cloned(),
+                new: other.arg(field).cloned(),
+            })
+            .collect()
+    }
+
+    /// Get the rendered arg for an option
+    fn arg(&self, field: Field) -> Option<&OsString> {
+        use Field::*;
+        match field {
+            ByteCount => &self.byte_count,
+            Checksum => &self.checksum,
+            Data => &self.data,
+            DeviceUuid => &self.device_uuid,
+            Features => &self.features,
+            Force => &self.force,
+            Label => &self.label,
+            Metadata => &self.metadata,
+            Mixed => &self.mixed,
+            NoDiscard => &self.no_discard,
+            Nodesize => &self.nodesize,
+            Rootdir => &self.rootdir,
+            RuntimeFeatures => &self.runtime_features,
+            Sectorsize => &self.sectorsize,
+            Shrink => &self.shrink,
+            Uuid => &self.uuid,
+        }
+        .as_ref()
+    }
+
+    /// Allow formatting the device that backs the root filesystem.
+    ///
+    /// By default, formatting the block device (or disk) that `/` is mounted from
+    /// is refused with [`RootDevice`]. Only call this if you really mean it.
+    ///
+    /// # Examples
+    /// ```
+    /// # use mkfs_btrfs_rs::Error;
+    /// use mkfs_btrfs_rs::format::Formatter;
+    /// Formatter::options()
+    ///     .allow_dangerous()?;
+    /// # Ok::<(), Error>(())
+    /// ```
+    pub fn allow_dangerous(mut self) -> Result<Self> {
+        self.values.allow_dangerous = true;
+        Ok(self)
+    }
+    /// Write out a shell one-liner that creates an image file and formats it with these options
+    ///
+    /// The image is `byte_count` bytes long, or [`DEFAULT_IMAGE_SIZE`] if that isn't set.
+    ///
+    /// # Examples
+    /// ```
+    /// # use mkfs_btrfs_rs::Error;
+    /// use mkfs_btrfs_rs::format::Formatter;
+    /// let script = Formatter::options()
+    ///     .label("My Volume")?
+    ///     .to_shell_script("test.btrfs");
+    /// assert_eq!(
+    ///     "truncate --size=536870912 test.btrfs && mkfs.btrfs '--label=My Volume' test.btrfs",
+    ///     script,
+    /// );
+    /// # Ok::<(), Error>(())
+    /// ```
+    pub fn to_shell_script(&self, device: &str) -> String {
+        let size = self.values.byte_count.unwrap_or(DEFAULT_IMAGE_SIZE);
+        let device = shell_quote(device);
+        let mut script = format!("truncate --size={size} {device} && {DEFAULT_BINARY}");
+        for arg in self.to_args() {
+            script.push(' ');
+            script.push_str(&shell_quote(&arg.to_string_lossy()));
+        }
+        script.push(' ');
+        script.push_str(&device);
+        script
+    }
+
+    /// Pass an arg to mkfs.btrfs as-is, after all the other options
+    ///
+    /// An escape hatch for options this crate doesn't support yet. The arg isn't checked.
+    ///
+    /// # Examples
+    /// ```
+    /// # use mkfs_btrfs_rs::Error;
+    /// use mkfs_btrfs_rs::format::Formatter;
+    /// Formatter::options()
+    ///     .extra_arg("--quiet")?;
+    /// # Ok::<(), Error>(())
+    /// ```
+    pub fn extra_arg<S: Into<OsString>>(mut self, arg: S) -> Result<Self> {
+        self.extra_args.push(arg.into());
+        Ok(self)
+    }
+    /// Pass several args to mkfs.btrfs as-is, after all the other options
+    ///
+    /// See [`extra_arg`](Self::extra_arg).
+    ///
+    /// # Examples
+    /// ```
+    /// # use mkfs_btrfs_rs::Error;
+    /// use mkfs_btrfs_rs::format::Formatter;
+    /// Formatter::options()
+    ///     .extra_args(["--subvol", "home"])?;
+    /// # Ok::<(), Error>(())
+    /// ```
+    pub fn extra_args<S: Into<OsString>>(
+        mut self,
+        args: impl IntoIterator<Item = S>,
+    ) -> Result<Self> {
+        self.extra_args.extend(args.into_iter().map(Into::into));
+        Ok(self)
+    }
+    /// Set an environment variable for mkfs.btrfs
+    ///
+    /// mkfs.btrfs runs with `LC_ALL=C` and `LANG=C` by default, so its output can be parsed
+    /// whatever the caller's locale; setting either here overrides that.
+    ///
+    /// # Examples
+    /// ```
+    /// # use mkfs_btrfs_rs::Error;
+    /// use mkfs_btrfs_rs::format::Formatter;
+    /// Formatter::options()
+    ///     .env("TMPDIR", "/var/tmp")?;
+    /// assert!(Formatter::options().env("A=B", "C").is_err());
+    /// # Ok::<(), Error>(())
+    /// ```
+    pub fn env<K: Into<OsString>, V: Into<OsString>>(mut self, key: K, value: V) -> Result<Self> {
+        let key = key.into();
+        if key.is_empty() || key.to_string_lossy().contains(['=', '\0']) {
+            return Err(rejected(
+                "env",
+                key.to_string_lossy(),
+                format!("env ( = {key:?} )\nMust be a non-empty name, without '=' or NUL"),
+            ));
+        }
+        self.values.env.push((key, value.into()));
+        Ok(self)
+    }
+    /// Let mkfs.btrfs use the whole device, and record how big it is in the [`FormatReport`]
+    ///
+    /// The size is measured just before [`format_reported`](Formatter::format_reported)
+    /// runs mkfs.btrfs, and lands in [`FormatReport::device_size_bytes`]: the capacity
+    /// of a block device (with the `BLKGETSIZE64` ioctl), or the length of a file.
+    /// Conflicts with `byte_count`, which would stop mkfs.btrfs from filling the device.
+    ///
+    /// # Examples
+    /// ```
+    /// # use mkfs_btrfs_rs::Error;
+    /// use mkfs_btrfs_rs::format::Formatter;
+    /// Formatter::options()
+    ///     .fill_device()?;
+    /// assert!(Formatter::options().byte_count(1 << 30)?.fill_device().is_err());
+    /// # Ok::<(), Error>(())
+    /// ```
+    pub fn fill_device(mut self) -> Result<Self> {
+        if let Some(byte_count) = self.values.byte_count {
+            return Err(rejected(
+                "fill_device",
+                byte_count,
+                format!("byte_count ( = {byte_count} )\nMust not be set to fill the device"),
+            ));
+        }
+        self.values.fill_device = true;
+        Ok(self)
+    }
+    /// Zero the first and last MiB of the device before formatting, like `wipefs`
+    ///
+    /// Clears stale signatures, like old partition tables, RAID metadata and btrfs superblock
+    /// mirrors, that `--force` leaves behind and that can confuse other tools.
+    /// Happens just before mkfs.btrfs runs, after the device passes the same checks
+    /// as [`format`](Formatter::format); [`into_command`](Formatter::into_command) and
+    /// [`format_preview`](Formatter::format_preview) never wipe anything.
+    ///
+    /// # Examples
+    /// ```
+    /// # use mkfs_btrfs_rs::Error;
+    /// use mkfs_btrfs_rs::format::Formatter;
+    /// Formatter::options()
+    ///     .wipe_signatures()?
+    ///     .force()?;
+    /// # Ok::<(), Error>(())
+    /// ```
+    pub fn wipe_signatures(mut self) -> Result<Self> {
+        self.values.wipe_signatures = true;
+        Ok(self)
+    }
+    /// Append mkfs.btrfs' stdout, then its stderr, to a log file after every run
+    ///
+    /// The output is still returned as usual. The file is created if it doesn't exist,
+    /// and opened before mkfs.btrfs runs, so a bad path fails with [`IoError`]
+    /// without formatting anything. Streaming runs, like
+    /// [`format_with_callbacks`](Formatter::format_with_callbacks), aren't logged.
+    ///
+    /// # Examples
+    /// ```
+    /// # use mkfs_btrfs_rs::Error;
+    /// use mkfs_btrfs_rs::format::Formatter;
+    /// Formatter::options()
+    ///     .log_to("/var/log/provisioning/mkfs.log")?;
+    /// # Ok::<(), Error>(())
+    /// ```
+    pub fn log_to<P: Into<PathBuf>>(mut self, path: P) -> Result<Self> {
+        self.values.log_to = Some(path.into());
+        Ok(self)
+    }
+    /// Run mkfs.btrfs in the caller's locale, instead of forcing `LC_ALL=C` and `LANG=C`
+    ///
+    /// Its messages may then be translated, which can keep [`FormatReport`]
+    /// and error classification from recognizing them.
+    ///
+    /// # Examples
+    /// ```
+    /// # use mkfs_btrfs_rs::Error;
+    /// use mkfs_btrfs_rs::format::Formatter;
+    /// Formatter::options()
+    ///     .native_locale()?;
+    /// # Ok::<(), Error>(())
+    /// ```
+    pub fn native_locale(mut self) -> Result<Self> {
+        self.values.native_locale = true;
+        Ok(self)
+    }
+    /// Set several environment variables for mkfs.btrfs
+    ///
+    /// See [`env`](Self::env).
+    ///
+    /// # Examples
+    /// ```
+    /// # use mkfs_btrfs_rs::Error;
+    /// use mkfs_btrfs_rs::format::Formatter;
+    /// Formatter::options()
+    ///     .envs([("LC_ALL", "de_DE.UTF-8"), ("TMPDIR", "/var/tmp")])?;
+    /// # Ok::<(), Error>(())
+    /// ```
+    pub fn envs<K: Into<OsString>, V: Into<OsString>>(
+        self,
+        vars: impl IntoIterator<Item = (K, V)>,
+    ) -> Result<Self> {
+        vars.into_iter()
+            .try_fold(self, |options, (key, value)| options.env(key, value))
+    }
+    /// Drop every arg added with [`extra_arg`](Self::extra_arg) or [`extra_args`](Self::extra_args)
+    ///
+    /// Useful when reusing a base config.
+    ///
+    /// # Examples
+    /// ```
+    /// # use mkfs_btrfs_rs::Error;
+    /// use mkfs_btrfs_rs::format::Formatter;
+    /// let base = Formatter::options()
+    ///     .extra_arg("--quiet")?;
+    /// let options = base.clone().clear_extra_args();
+    /// # Ok::<(), Error>(())
+    /// ```
+    pub fn clear_extra_args(mut self) -> Self {
+        self.extra_args.clear();
+        self
+    }
+
+    /// Dump FormatterOptions as they'll be passed to mkfs.btrfs
+    ///
+    /// # Examples
+    /// ```
+    /// use mkfs_btrfs_rs::format::Formatter;
+    /// Formatter::options()
+    ///     .dump_args();
+    /// ```
+    pub fn dump_args(self) -> Self {
+        println!("{:#?}", self.to_args());
+        self
+    }
+
+    /// Bake FormatterOptions into a Formatter
+    ///
+    /// Each setter validates its own argument, but options that depend on each other
+    /// aren't checked here; mkfs.btrfs will reject them when formatting.
+    /// Use [`try_build`](Self::try_build) to catch those early.
+    ///
+    /// # Examples
+    /// ```
+    /// # use mkfs_btrfs_rs::Error;
+    /// use mkfs_btrfs_rs::format::Formatter;
     /// Formatter::options()
     ///     .label("my-Btrfs-volume")?
-    ///     .rootdir("./testdir")?
+    ///     .rootdir("./src")?
     ///     .shrink()?
-    ///     .build()
-    ///     .format("./test.btrfs")?;
+    ///     .build();
     /// # Ok::<(), Error>(())
     /// ```
-    pub fn format<P: AsRef<Path>>(mut self, device: P) -> IoResult<Output> {
-        device.as_ref().try_exists()?;
-        self.args.push(OsString::from(device.as_ref()));
-        // FIXME: Parse the output of mkfs.btrfs and send it back properly.
-        Command::new("mkfs.btrfs").args(self.args).output()
+    pub fn build(&self) -> Formatter {
+        let args = self.to_args();
+        Formatter {
+            args,
+            binary: PathBuf::from(DEFAULT_BINARY),
+            check_binary: PathBuf::from(DEFAULT_CHECK_BINARY),
+            values: self.values.clone(),
+            skip_existence_check: false,
+            stdin: None,
+        }
     }
+
+    /// Validate options that depend on each other, then bake FormatterOptions into a Formatter
+    ///
+    /// Runs the same checks as [`validate`](Self::validate).
+    ///
+    /// # Examples
+    /// ```
+    /// # use mkfs_btrfs_rs::Error;
+    /// use mkfs_btrfs_rs::format::{DataProfile, Formatter};
+    /// Formatter::options()
+    ///     .mixed()?
+    ///     .profile(DataProfile::Dup)?
+    ///     .try_build()?;
+    /// # Ok::<(), Error>(())
+    /// ```
+    pub fn try_build(&self) -> Result<Formatter> {
+        self.validate()?;
+        Ok(self.build())
+    }
+
+    /// Check that the options are consistent, without building a Formatter
+    ///
+    /// Returns the first [`Violation`] found, as an [`ArgumentError`]. Checks that:
+    /// - `sectorsize` is a power of 2, from 4096 to 65536
+    /// - `sectorsize` is this system's page size, with [`page_size_check`](Self::page_size_check)
+    /// - `shrink` is only used with a rootdir, unless relaxed with [`shrink_check`](Self::shrink_check)
+    /// - `nodesize` is no smaller than `sectorsize`
+    /// - In mixed mode, data and metadata use the same profile
+    /// - In mixed mode, nodesize and sectorsize are equal
+    /// - Otherwise, `byte_count` is at least the data and metadata profiles'
+    ///   [`min_byte_count`](DataProfile::min_byte_count), taking unset metadata as `dup`
+    ///   like mkfs.btrfs does
+    /// - A 32-byte checksum isn't paired with a 4096-byte `sectorsize`, with [`checksum_check`](Self::checksum_check)
+    ///
+    /// # Examples
+    /// ```
+    /// # use mkfs_btrfs_rs::Error;
+    /// use mkfs_btrfs_rs::format::Formatter;
+    /// let options = Formatter::options()
+    ///     .nodesize(4096)?
+    ///     .sectorsize(16384)?;
+    /// assert!(options.validate().is_err());
+    /// # Ok::<(), Error>(())
+    /// ```
+    pub fn validate(&self) -> Result<()> {
+        match self.violations().into_iter().next() {
+            Some(violation) => Err(violation.into()),
+            None => Ok(()),
+        }
+    }
+
+    /// Every [`Violation`] in these options, in the order [`validate`](Self::validate) checks them
+    ///
+    /// Unlike `validate`, this doesn't stop at the first problem,
+    /// so they can all be shown at once.
+    ///
+    /// # Examples
+    /// ```
+    /// # use mkfs_btrfs_rs::Error;
+    /// use mkfs_btrfs_rs::{format::{DataProfile, Formatter}, validate::Violation};
+    /// let options = Formatter::options()
+    ///     .mixed()?
+    ///     .data(DataProfile::Single)?
+    ///     .metadata(DataProfile::Dup)?
+    ///     .shrink()?;
+    /// assert_eq!(2, options.violations().len());
+    /// assert!(options.violations().contains(&Violation::ShrinkWithoutRootdir));
+    /// # Ok::<(), Error>(())
+    /// ```
+    pub fn violations(&self) -> Vec<Violation> {
+        let values = &self.values;
+        validate::violations(&validate::Settings {
+            byte_count: values.byte_count,
+            heavy_checksum: values
+                .heavy_checksum()
+                .filter(|_| values.checksum_check == Strictness::Deny),
+            sectorsize: values.sectorsize,
+            nodesize: values.nodesize,
+            data: values.data,
+            metadata: values.metadata,
+            deny_page_size: (values.page_size_check == Strictness::Deny).then(device::page_size),
+            deny_shrink: values.shrink_check() == Strictness::Deny,
+            mixed: self.is_mixed(),
+            shrink: self.shrink.is_some(),
+            rootdir: self.rootdir.is_some(),
+            uuid: self.uuid.as_ref().and_then(arg_value).map(str::to_owned),
+            device_uuid: values.device_uuid.clone(),
+        })
+    }
+
+    /// Whether data and metadata block groups will be mixed,
+    /// either through `mixed` or the `mixed-bg` feature
+    fn is_mixed(&self) -> bool {
+        self.mixed.is_some()
+            || self
+                .features
+                .as_ref()
+                .and_then(arg_value)
+                .is_some_and(|features| features.split(',').any(|f| f == "mixed-bg"))
+    }
+}
+
+/// Parse args, like those from [`Formatter::iter_args`], back into options
+///
+/// Each known `--option=value` or flag goes through the same validation as its setter.
+/// Anything else is kept as an [`extra_arg`](FormatterOptions::extra_arg), so nothing is lost.
+///
+/// # Examples
+/// ```
+/// # use mkfs_btrfs_rs::Error;
+/// use mkfs_btrfs_rs::format::{Formatter, FormatterOptions};
+/// use std::ffi::OsString;
+/// let options = Formatter::options()
+///     .label("imported")?
+///     .force()?;
+/// let args: Vec<OsString> = options.build().iter_args().map(OsString::from).collect();
+/// assert_eq!(options, FormatterOptions::try_from(args.as_slice())?);
+/// # Ok::<(), Error>(())
+/// ```
+impl TryFrom<&[OsString]> for FormatterOptions {
+    type Error = crate::Error;
+
+    fn try_from(args: &[OsString]) -> Result<Self> {
+        let mut options = Self::default();
+        for arg in args {
+            let Some(text) = arg.to_str() else {
+                options = options.extra_arg(arg)?;
+                continue;
+            };
+            let (option, value) = match text.split_once('=') {
+                Some((option, value)) => (option, Some(value)),
+                None => (text, None),
+            };
+            options = match (option, value) {
+                ("--byte-count", Some(value)) => {
+                    options.byte_count(parse_number("byte_count", "--byte-count", value)?)?
+                }
+                ("--checksum", Some(value)) => options.checksum(value.parse()?)?,
+                ("--data", Some(value)) => options.data(value.parse()?)?,
+                ("--device-uuid", Some(value)) => options.device_uuid(value)?,
+                ("--features", Some(value)) => options.features(value.split(','))?,
+                ("--force", None) => options.force()?,
+                // Rendered by `label` after `allow_empty_label`
+                ("--label", Some("")) => options.allow_empty_label()?.label("")?,
+                ("--label", Some(value)) => options.label(value)?,
+                ("--metadata", Some(value)) => options.metadata(value.parse()?)?,
+                ("--mixed", None) => options.mixed()?,
+                ("--nodiscard", None) => options.no_discard()?,
+                ("--nodesize", Some(value)) => {
+                    options.nodesize(parse_number("nodesize", "--nodesize", value)?)?
+                }
+                ("--rootdir", Some(value)) => options.rootdir(value)?,
+                ("--runtime-features", Some(value)) => {
+                    options.runtime_features(value.split(','))?
+                }
+                ("--sectorsize", Some(value)) => {
+                    options.sectorsize(parse_number("sectorsize", "--sectorsize", value)?)?
+                }
+                ("--shrink", None) => options.shrink()?,
+                ("--uuid", Some(value)) => options.uuid(value)?,
+                _ => options.extra_arg(arg)?,
+            };
+        }
+        Ok(options)
+    }
+}
+
+/// Copy the contents of `src` into `dest`, merging directories, for [`FormatterOptions::rootdirs`]
+#[cfg(feature = "tempfile")]
+fn merge_dir(src: &Path, dest: &Path, policy: ConflictPolicy) -> Result<()> {
+    for entry in std::fs::read_dir(src)? {
+        let entry = entry?;
+        let kind = entry.file_type()?;
+        let target = dest.join(entry.file_name());
+        if let Ok(existing) = target.symlink_metadata() {
+            if kind.is_dir() && existing.is_dir() {
+                merge_dir(&entry.path(), &target, policy)?;
+                continue;
+            }
+            match policy {
+                ConflictPolicy::FirstWins => continue,
+                ConflictPolicy::LastWins if existing.is_dir() => std::fs::remove_dir_all(&target)?,
+                ConflictPolicy::LastWins => std::fs::remove_file(&target)?,
+                ConflictPolicy::Error => {
+                    return Err(rejected(
+                        "rootdirs",
+                        entry.path().display(),
+                        format!(
+                            "rootdirs ( = {} )\nConflicts with {} from an earlier rootdir",
+                            entry.path().display(),
+                            target.display()
+                        ),
+                    ))
+                }
+            }
+        }
+        if kind.is_dir() {
+            std::fs::create_dir(&target)?;
+            merge_dir(&entry.path(), &target, policy)?;
+            std::fs::set_permissions(&target, entry.metadata()?.permissions())?;
+        } else if kind.is_symlink() {
+            copy_symlink(&entry.path(), &target)?;
+        } else {
+            std::fs::copy(entry.path(), &target)?;
+        }
+    }
+    Ok(())
+}
+
+/// Recreate the symlink at `src` as `dest`, pointing at the same target
+#[cfg(all(feature = "tempfile", unix))]
+fn copy_symlink(src: &Path, dest: &Path) -> Result<()> {
+    Ok(std::os::unix::fs::symlink(std::fs::read_link(src)?, dest)?)
+}
+
+/// Symlinks can't be recreated portably on this platform
+#[cfg(all(feature = "tempfile", not(unix)))]
+fn copy_symlink(_src: &Path, _dest: &Path) -> Result<()> {
+    Err(UnsupportedPlatform(std::env::consts::OS))
+}
+
+/// Walk `dir` for [`Formatter::rootdir_stats`], keeping paths relative to the rootdir
+///
+/// Files with more than one link are grouped in `inodes`; each inode's size is counted once.
+#[cfg(unix)]
+fn collect_stats(
+    dir: &Path,
+    relative: &Path,
+    stats: &mut RootdirStats,
+    inodes: &mut std::collections::HashMap<(u64, u64), Vec<PathBuf>>,
+) -> Result<()> {
+    use std::os::unix::fs::MetadataExt;
+    for entry in std::fs::read_dir(dir)? {
+        let entry = entry?;
+        let metadata = entry.path().symlink_metadata()?;
+        let path = relative.join(entry.file_name());
+        if metadata.is_dir() {
+            collect_stats(&entry.path(), &path, stats, inodes)?;
+            continue;
+        }
+        stats.file_count += 1;
+        if metadata.nlink() > 1 {
+            let links = inodes.entry((metadata.dev(), metadata.ino())).or_default();
+            links.push(path);
+            if links.len() > 1 {
+                continue;
+            }
+        }
+        stats.total_bytes += metadata.len();
+    }
+    Ok(())
+}
+
+/// Hardlinks can't be told apart without inode numbers on this platform
+#[cfg(not(unix))]
+fn collect_stats(
+    _dir: &Path,
+    _relative: &Path,
+    _stats: &mut RootdirStats,
+    _inodes: &mut std::collections::HashMap<(u64, u64), Vec<PathBuf>>,
+) -> Result<()> {
+    Err(UnsupportedPlatform(std::env::consts::OS))
+}
+
+/// Add up the sizes of every file in a directory, recursively.
+///
+/// Symlinks aren't followed.
+fn dir_size(dir: &Path) -> IoResult<u64> {
+    let mut size = 0;
+    for entry in std::fs::read_dir(dir)? {
+        let entry = entry?;
+        let metadata = entry.metadata()?;
+        size += if metadata.is_dir() {
+            dir_size(&entry.path())?
+        } else {
+            metadata.len()
+        };
+    }
+    Ok(size)
+}
+
+/// Reject the `value` a setter was given for `option`, logging it if the `log` feature is enabled
+///
+/// Options that mkfs.btrfs documents get a link to the manual, after the message.
+fn rejected(option: &'static str, value: impl std::fmt::Display, message: String) -> crate::Error {
+    let message = match manual_anchor(option) {
+        Some(anchor) => format!("{message}\nSee {MANUAL_BASE}#{anchor}"),
+        None => message,
+    };
+    #[cfg(feature = "log")]
+    crate::log::emit(&crate::log::Rejected {
+        option,
+        value: &value.to_string(),
+        message: &message,
+    });
+    #[cfg(not(feature = "log"))]
+    let _ = value;
+    ArgumentError { option, message }
+}
+
+/// The anchor of an option in the mkfs.btrfs manual, if it's documented there
+fn manual_anchor(option: &str) -> Option<&'static str> {
+    Some(match option {
+        "profile" => PROFILES_ANCHOR,
+        "byte_count" | "checksum" | "device_uuid" | "features" | "label" | "nodesize"
+        | "rootdir" | "runtime_features" | "sectorsize" | "uuid" => OPTIONS_ANCHOR,
+        _ => return None,
+    })
+}
+
+/// Read an environment variable, if it's set to something other than whitespace
+fn env_var(name: &'static str) -> Result<Option<String>> {
+    match std::env::var(name) {
+        // `MKFS_BTRFS_FEATURES= cmd` reads as unsetting it, not as asking for an empty feature
+        Ok(value) if value.trim().is_empty() => Ok(None),
+        Ok(value) => Ok(Some(value)),
+        Err(std::env::VarError::NotPresent) => Ok(None),
+        Err(std::env::VarError::NotUnicode(value)) => Err(rejected(
+            name,
+            value.to_string_lossy(),
+            format!(
+                "{name} ( = {} )\nMust be valid UTF-8",
+                value.to_string_lossy()
+            ),
+        )),
+    }
+}
+
+/// Read a flag from an environment variable
+fn env_flag(name: &'static str) -> Result<bool> {
+    match env_var(name)?.as_deref() {
+        None | Some("0" | "false" | "no") => Ok(false),
+        Some("1" | "true" | "yes") => Ok(true),
+        Some(value) => Err(rejected(
+            name,
+            value,
+            format!("{name} ( = {value} )\nMust be one of 1, true, yes, 0, false, no"),
+        )),
+    }
+}
+
+/// Read a number from an environment variable, if it's set
+fn env_number<T: std::str::FromStr>(name: &'static str) -> Result<Option<T>> {
+    env_var(name)?
+        .map(|value| parse_number(name, name, &value))
+        .transpose()
+}
+
+/// Parse a number from an environment variable or arg, naming it as `name` if it isn't one
+fn parse_number<T: std::str::FromStr>(option: &'static str, name: &str, value: &str) -> Result<T> {
+    value.parse().map_err(|_| {
+        rejected(
+            option,
+            value,
+            format!("{name} ( = {value} )\nMust be a number"),
+        )
+    })
+}
+
+/// Render features into a list, making sure none of them would break up the `,`-separated arg
+fn feature_list<T: std::fmt::Display>(
+    option: &'static str,
+    features: impl IntoIterator<Item = T>,
+) -> Result<Vec<String>> {
+    features
+        .into_iter()
+        .map(|feature| {
+            let feature = feature.to_string();
+            if feature.contains(|c: char| c == ',' || c.is_whitespace()) {
+                return Err(rejected(
+                    option,
+                    &feature,
+                    format!("{option} ( = \"{feature}\" )\nMust not contain commas or whitespace"),
+                ));
+            }
+            Ok(feature)
+        })
+        .collect()
+}
+
+/// Quote a word for a POSIX shell, if it needs quoting
+fn shell_quote(word: &str) -> String {
+    let safe = |c: char| c.is_ascii_alphanumeric() || "-_=.,/:+^@%".contains(c);
+    if !word.is_empty() && word.chars().all(safe) {
+        word.to_owned()
+    } else {
+        format!("'{}'", word.replace('\'', r"'\''"))
+    }
+}
+
+/// Sort the list in a rendered `--option=a,b,c` arg
+fn sorted_list(arg: &OsString) -> OsString {
+    let Some((option, list)) = arg.to_str().and_then(|arg| arg.split_once('=')) else {
+        return arg.clone();
+    };
+    let mut list: Vec<&str> = list.split(',').collect();
+    list.sort_unstable();
+    OsString::from(format!("{option}={}", list.join(",")))
+}
+
+/// Get the value of a rendered `--option=value` arg
+fn arg_value(arg: &OsString) -> Option<&str> {
+    arg.to_str()?.split_once('=').map(|(_, value)| value)
+}
+
+/// List the features a mkfs.btrfs binary knows about, from `mkfs.btrfs -O list-all`
+fn supported_features_of(binary: &Path) -> Result<Vec<String>> {
+    let output = Command::new(binary).args(["-O", "list-all"]).output()?;
+    if !output.status.success() {
+        return Err(CommandFailed {
+            status: output.status,
+            stderr: output.stderr_str().into_owned(),
+        });
+    }
+    // Older versions of mkfs.btrfs print the list to stderr
+    let mut features = report::parse_feature_list(&output.stdout_str());
+    features.extend(report::parse_feature_list(&output.stderr_str()));
+    Ok(features)
+}
+
+/// Append the output of mkfs.btrfs to a log file, if there is one
+fn write_log(log: Option<File>, output: &Output) -> IoResult<()> {
+    if let Some(mut log) = log {
+        log.write_all(&output.stdout)?;
+        log.write_all(&output.stderr)?;
+    }
+    Ok(())
+}
+
+/// Find the option getopt complained about, in `unrecognized option '--compress'`
+/// (glibc) or `unrecognized option: --compress` (musl)
+fn unrecognized_option(stderr: &str) -> Option<String> {
+    let (_, rest) = stderr.split_once("unrecognized option")?;
+    let option = rest
+        .trim_start_matches([':', ' '])
+        .split(char::is_whitespace)
+        .next()?
+        .trim_matches(['\'', '`', '"']);
+    (!option.is_empty()).then(|| option.to_owned())
+}
+
+/// Make sure a node or sector size is a power of 2, no larger than `max`
+fn validate_block_size(option: &'static str, value: usize, max: usize) -> Result<usize> {
+    if value.is_power_of_two() && value <= max {
+        Ok(value)
+    } else {
+        Err(rejected(
+            option,
+            value,
+            format!("{option} ( = {value} )\nMust be a power of 2, and <= {max}"),
+        ))
+    }
+}
+
+/// Make sure `uuid` is a hyphenated UUID that btrfs will accept
+fn validate_uuid(option: &'static str, uuid: &str) -> Result<()> {
+    let groups: Vec<&str> = uuid.split('-').collect();
+    let well_formed = groups.len() == 5
+        && groups
+            .iter()
+            .zip([8, 4, 4, 4, 12])
+            .all(|(group, len)| group.len() == len && group.chars().all(|c| c.is_ascii_hexdigit()));
+    if !well_formed {
+        return Err(rejected(
+            option,
+            uuid,
+            format!(
+                "{option} ( = {uuid} )\nMust be of the form xxxxxxxx-xxxx-xxxx-xxxx-xxxxxxxxxxxx"
+            ),
+        ));
+    }
+    if uuid.chars().all(|c| c == '0' || c == '-') {
+        return Err(rejected(
+            option,
+            uuid,
+            format!(
+                "{option} ( = {uuid} )\nThe nil UUID is reserved, and can't be used for a filesystem"
+            ),
+        ));
+    }
+    Ok(())
+}
+
+/// Formats anything that can be Btrfs-formatted.
+#[derive(Debug, Hash, Eq, PartialEq, Ord, PartialOrd)]
+pub struct Formatter {
+    args: Vec<OsString>,
+    binary: PathBuf,
+    check_binary: PathBuf,
+    values: OptionValues,
+    skip_existence_check: bool,
+    stdin: Option<Vec<u8>>,
+}
+
+impl Formatter {
+    /// Specify FormatterOptions first, then build a formatter
+    ///
+    /// ```
+    /// # use mkfs_btrfs_rs::Error;
+    /// use mkfs_btrfs_rs::format::Formatter;
+    /// let options = Formatter::options()
+    /// /* set options here...*/;
+    /// options.build().format("./test.btrfs")?;
+    /// # Ok::<(), Error>(())
+    /// ```
+    pub fn options() -> FormatterOptions {
+        FormatterOptions::default()
+    }
+    /// Start with options for a single, unreplicated copy of data and metadata
+    ///
+    /// Same as `Formatter::options().profile(DataProfile::Single)`
+    ///
+    /// # Examples
+    /// ```
+    /// # use mkfs_btrfs_rs::Error;
+    /// use mkfs_btrfs_rs::format::Formatter;
+    /// Formatter::single()?
+    ///     .label("scratch")?
+    ///     .build();
+    /// # Ok::<(), Error>(())
+    /// ```
+    pub fn single() -> Result<FormatterOptions> {
+        Self::options().profile(DataProfile::Single)
+    }
+    /// Start with options for data and metadata mirrored across 2 devices
+    ///
+    /// Same as `Formatter::options().profile(DataProfile::Raid1)`
+    ///
+    /// # Examples
+    /// ```
+    /// # use mkfs_btrfs_rs::Error;
+    /// use mkfs_btrfs_rs::format::Formatter;
+    /// Formatter::raid1()?
+    ///     .label("mirror")?
+    ///     .build();
+    /// # Ok::<(), Error>(())
+    /// ```
+    pub fn raid1() -> Result<FormatterOptions> {
+        Self::options().profile(DataProfile::Raid1)
+    }
+    /// Start with options for data and metadata striped and mirrored across 4 or more devices
+    ///
+    /// Same as `Formatter::options().profile(DataProfile::Raid10)`
+    ///
+    /// # Examples
+    /// ```
+    /// # use mkfs_btrfs_rs::Error;
+    /// use mkfs_btrfs_rs::format::Formatter;
+    /// Formatter::raid10()?
+    ///     .label("array")?
+    ///     .build();
+    /// # Ok::<(), Error>(())
+    /// ```
+    pub fn raid10() -> Result<FormatterOptions> {
+        Self::options().profile(DataProfile::Raid10)
+    }
+    /// Run a different mkfs.btrfs binary than the one in `PATH`
+    ///
+    /// # Examples
+    /// ```
+    /// use mkfs_btrfs_rs::format::Formatter;
+    /// Formatter::options()
+    ///     .build()
+    ///     .with_binary("/usr/local/sbin/mkfs.btrfs");
+    /// ```
+    pub fn with_binary<P: Into<PathBuf>>(mut self, binary: P) -> Self {
+        self.binary = binary.into();
+        self
+    }
+    /// The mkfs.btrfs binary this Formatter runs, as given to [`with_binary`](Self::with_binary)
+    ///
+    /// Defaults to `mkfs.btrfs`, which is looked up in `PATH` when it runs.
+    ///
+    /// # Examples
+    /// ```
+    /// use mkfs_btrfs_rs::format::Formatter;
+    /// use std::path::Path;
+    /// let formatter = Formatter::options()
+    ///     .build()
+    ///     .with_binary("/usr/local/sbin/mkfs.btrfs");
+    /// assert_eq!(Path::new("/usr/local/sbin/mkfs.btrfs"), formatter.binary());
+    /// ```
+    pub fn binary(&self) -> &Path {
+        &self.binary
+    }
+    /// Run a different btrfs binary than the one in `PATH`, for [`format_verified`](Self::format_verified)
+    ///
+    /// # Examples
+    /// ```
+    /// use mkfs_btrfs_rs::format::Formatter;
+    /// Formatter::options()
+    ///     .build()
+    ///     .with_check_binary("/usr/local/sbin/btrfs");
+    /// ```
+    pub fn with_check_binary<P: Into<PathBuf>>(mut self, binary: P) -> Self {
+        self.check_binary = binary.into();
+        self
+    }
+    /// Feed `input` to mkfs.btrfs' stdin, e.g. to answer a prompt
+    ///
+    /// Otherwise stdin is `/dev/null`, so mkfs.btrfs can never hang waiting for input.
+    ///
+    /// # Examples
+    /// ```
+    /// use mkfs_btrfs_rs::format::Formatter;
+    /// Formatter::options()
+    ///     .build()
+    ///     .with_stdin("y\n");
+    /// ```
+    pub fn with_stdin<B: Into<Vec<u8>>>(mut self, input: B) -> Self {
+        self.stdin = Some(input.into());
+        self
+    }
+    /// Summarize the configuration this Formatter was built with
+    ///
+    /// Taken from the options, so it doesn't see args added by
+    /// [`map_args`](Self::map_args) or [`extra_args`](FormatterOptions::extra_args).
+    ///
+    /// # Examples
+    /// ```
+    /// # use mkfs_btrfs_rs::Error;
+    /// use mkfs_btrfs_rs::format::{ChecksumAlgorithm, DataProfile, Formatter};
+    /// let summary = Formatter::options()
+    ///     .profile(DataProfile::Raid1)?
+    ///     .checksum(ChecksumAlgorithm::XxHash)?
+    ///     .build()
+    ///     .describe();
+    /// assert_eq!(Some(ChecksumAlgorithm::XxHash), summary.checksum);
+    /// println!("{summary}");
+    /// # Ok::<(), Error>(())
+    /// ```
+    pub fn describe(&self) -> FormatterSummary {
+        let values = &self.values;
+        FormatterSummary {
+            data: values.data,
+            metadata: values.metadata,
+            checksum: values.checksum,
+            byte_count: values.byte_count,
+            nodesize: values.nodesize,
+            sectorsize: values.sectorsize,
+            features: values.features.clone(),
+        }
+    }
+    /// The byte offsets mkfs.btrfs will write superblocks to
+    ///
+    /// The primary superblock is always at 64KiB, with mirrors at 64MiB and 256GiB.
+    /// If `byte_count` is set, only the superblocks that fit in it are listed.
+    /// The offsets are fixed whatever the sectorsize, so it isn't taken as an argument.
+    /// Handy for checking a formatted device by reading them back.
+    ///
+    /// # Examples
+    /// ```
+    /// # use mkfs_btrfs_rs::Error;
+    /// use mkfs_btrfs_rs::format::Formatter;
+    /// let offsets = Formatter::options()
+    ///     .byte_count(1 << 30)?
+    ///     .build()
+    ///     .superblock_offsets();
+    /// assert_eq!(vec![65536, 67108864], offsets);
+    /// # Ok::<(), Error>(())
+    /// ```
+    pub fn superblock_offsets(&self) -> Vec<u64> {
+        SUPERBLOCK_OFFSETS
+            .into_iter()
+            // Not Option::is_none_or, which needs Rust 1.82
+            .filter(|&offset| match self.values.byte_count {
+                Some(size) => offset + SUPERBLOCK_SIZE <= size,
+                None => true,
+            })
+            .collect()
+    }
+    /// Edit the args mkfs.btrfs will be run with, before the device
+    ///
+    /// An escape hatch for anything the options don't cover: args can be reordered,
+    /// removed or added. They aren't checked again, so anything goes.
+    ///
+    /// # Examples
+    /// ```
+    /// # use mkfs_btrfs_rs::Error;
+    /// use mkfs_btrfs_rs::format::Formatter;
+    /// let formatter = Formatter::options()
+    ///     .force()?
+    ///     .label("mapped")?
+    ///     .build()
+    ///     .map_args(|args| args.retain(|arg| arg != "--force"));
+    /// assert!(formatter.iter_args().all(|arg| arg != "--force"));
+    /// # Ok::<(), Error>(())
+    /// ```
+    pub fn map_args<F: FnOnce(&mut Vec<OsString>)>(mut self, f: F) -> Self {
+        f(&mut self.args);
+        self
+    }
+    /// Iterate over the args mkfs.btrfs will be run with, before the device
+    ///
+    /// # Examples
+    /// ```
+    /// # use mkfs_btrfs_rs::Error;
+    /// use mkfs_btrfs_rs::format::Formatter;
+    /// let formatter = Formatter::options()
+    ///     .label("my-Btrfs-volume")?
+    ///     .build();
+    /// for arg in formatter.iter_args() {
+    ///     println!("{}", arg.to_string_lossy());
+    /// }
+    /// # Ok::<(), Error>(())
+    /// ```
+    pub fn iter_args(&self) -> impl Iterator<Item = &OsStr> {
+        self.args.iter().map(OsString::as_os_str)
+    }
+    /// Don't check that the device exists before running mkfs.btrfs
+    ///
+    /// Useful for special files that [`Path::try_exists`] can't see, or to get mkfs.btrfs'
+    /// own error for a missing device. The trade-off is that a typo in the path is no longer
+    /// caught (with a clear [`ArgumentError`]) before mkfs.btrfs runs. Devices that do exist
+    /// are still checked as usual.
+    ///
+    /// # Examples
+    /// ```
+    /// use mkfs_btrfs_rs::format::Formatter;
+    /// Formatter::options()
+    ///     .build()
+    ///     .skip_existence_check();
+    /// ```
+    pub fn skip_existence_check(mut self) -> Self {
+        self.skip_existence_check = true;
+        self
+    }
+    /// Format a device with mkfs.btrfs
+    ///
+    /// # Examples
+    /// ```
+    /// # use mkfs_btrfs_rs::Error;
+    /// use mkfs_btrfs_rs::format::*;
+    /// Formatter::options()
+    ///     .label("my-Btrfs-volume")?
+    ///     .rootdir("./src")?
+    ///     .shrink()?
+    ///     .build()
+    ///     .format("./test.btrfs")?;
+    /// # Ok::<(), Error>(())
+    /// ```
+    ///
+    /// If the device is a regular file, it must be at least `byte_count` bytes long.
+    pub fn format<P: AsRef<Path>>(&self, device: P) -> Result<Output> {
+        // FIXME: Parse the output of mkfs.btrfs and send it back properly.
+        Ok(self.output(&mut self.prepare(device)?)?)
+    }
+    /// Start formatting a device, and hand back the running mkfs.btrfs
+    ///
+    /// The device is checked the same way as in [`format`](Self::format), but waiting,
+    /// killing and reading stdout and stderr (both piped) is up to the caller.
+    /// [`log_to`](FormatterOptions::log_to) has no effect, since the output isn't read here.
+    ///
+    /// # Examples
+    /// ```no_run
+    /// # use mkfs_btrfs_rs::Error;
+    /// use mkfs_btrfs_rs::format::*;
+    /// let child = Formatter::options()
+    ///     .build()
+    ///     .spawn("./test.btrfs")?;
+    /// let output = child.wait_with_output()?;
+    /// # Ok::<(), Error>(())
+    /// ```
+    pub fn spawn<P: AsRef<Path>>(&self, device: P) -> Result<Child> {
+        let mut command = self.prepare(device)?;
+        Ok(self.spawn_command(command.stdout(Stdio::piped()).stderr(Stdio::piped()))?)
+    }
+    /// Format an already-open file descriptor with mkfs.btrfs
+    ///
+    /// mkfs.btrfs inherits `fd` and opens it as `/dev/fd/<fd>`, so the caller
+    /// only needs to hand over the open device, not a path to it.
+    /// `fd` must be open, and can't be stdin, stdout or stderr, which mkfs.btrfs gets its own of.
+    ///
+    /// # Examples
+    /// ```no_run
+    /// # use mkfs_btrfs_rs::Error;
+    /// use mkfs_btrfs_rs::format::*;
+    /// use std::{fs::OpenOptions, os::unix::io::AsRawFd};
+    /// let device = OpenOptions::new().read(true).write(true).open("./test.btrfs")?;
+    /// Formatter::options()
+    ///     .build()
+    ///     .format_fd(device.as_raw_fd())?;
+    /// # Ok::<(), Error>(())
+    /// ```
+    #[cfg(target_os = "linux")]
+    pub fn format_fd(&self, fd: std::os::unix::io::RawFd) -> Result<Output> {
+        use std::os::unix::process::CommandExt;
+        if fd <= 2 || !device::fd_is_open(fd) {
+            return Err(rejected(
+                "fd",
+                fd,
+                format!("fd ( = {fd} )\nMust be an open file descriptor, other than stdin, stdout or stderr"),
+            ));
+        }
+        let mut command = self.prepare(format!("/dev/fd/{fd}"))?;
+        // SAFETY: inherit_fd only makes async-signal-safe calls
+        unsafe {
+            command.pre_exec(move || device::inherit_fd(fd));
+        }
+        Ok(self.output(&mut command)?)
+    }
+    /// Create a temp file `size` bytes long, and format it with mkfs.btrfs
+    ///
+    /// The file is deleted when the returned [`TempFile`](crate::temp::TempFile) is dropped.
+    ///
+    /// # Examples
+    /// ```no_run
+    /// # use mkfs_btrfs_rs::Error;
+    /// use mkfs_btrfs_rs::format::*;
+    /// let (output, image) = Formatter::options()
+    ///     .build()
+    ///     .format_tempfile(512 * 1024 * 1024)?;
+    /// println!("formatted {}", image.path().display());
+    /// # Ok::<(), Error>(())
+    /// ```
+    #[cfg(feature = "tempfile")]
+    pub fn format_tempfile(&self, size: u64) -> Result<(Output, crate::temp::TempFile)> {
+        let image = crate::temp::TempFile::new(size)?;
+        let output = self.format(&image)?;
+        Ok((output, image))
+    }
+    /// List the features the installed mkfs.btrfs knows about, from `mkfs.btrfs -O list-all`
+    ///
+    /// # Examples
+    /// ```no_run
+    /// # use mkfs_btrfs_rs::Error;
+    /// use mkfs_btrfs_rs::format::Formatter;
+    /// let supported = Formatter::supported_features()?;
+    /// assert!(supported.iter().any(|feature| feature == "no-holes"));
+    /// # Ok::<(), Error>(())
+    /// ```
+    pub fn supported_features() -> Result<Vec<String>> {
+        supported_features_of(Path::new(DEFAULT_BINARY))
+    }
+    /// Count the files in a would-be rootdir, and find the ones hardlinked together,
+    /// to help size the image before formatting it
+    ///
+    /// Only reads the directory. Symlinks aren't followed. Fails with
+    /// [`UnsupportedPlatform`] outside of unix, where hardlinks can't be found.
+    ///
+    /// # Examples
+    /// ```
+    /// # use mkfs_btrfs_rs::Error;
+    /// use mkfs_btrfs_rs::format::Formatter;
+    /// let stats = Formatter::rootdir_stats("./src")?;
+    /// assert!(stats.file_count > 0);
+    /// # Ok::<(), Error>(())
+    /// ```
+    pub fn rootdir_stats<P: AsRef<Path>>(dir: P) -> Result<RootdirStats> {
+        let dir = dir.as_ref();
+        if !dir.is_dir() {
+            return Err(rejected(
+                "rootdir",
+                dir.display(),
+                format!("rootdir is not a directory: {}", dir.display()),
+            ));
+        }
+        let mut stats = RootdirStats::default();
+        let mut inodes = std::collections::HashMap::new();
+        collect_stats(dir, Path::new(""), &mut stats, &mut inodes)?;
+        stats.hardlinks = inodes
+            .into_values()
+            .filter(|paths: &Vec<PathBuf>| paths.len() > 1)
+            .map(|mut paths| {
+                paths.sort();
+                paths
+            })
+            .collect();
+        stats.hardlinks.sort();
+        Ok(stats)
+    }
+    /// Time how long mkfs.btrfs takes to format a `sample_size`-byte temp image
+    /// with each checksum algorithm
+    ///
+    /// Every image is formatted with otherwise default options, and removed afterwards.
+    ///
+    /// # Examples
+    /// ```no_run
+    /// # use mkfs_btrfs_rs::Error;
+    /// use mkfs_btrfs_rs::format::*;
+    /// for (checksum, elapsed) in Formatter::benchmark_checksums(DEFAULT_IMAGE_SIZE)? {
+    ///     println!("{checksum}: {elapsed:?}");
+    /// }
+    /// # Ok::<(), Error>(())
+    /// ```
+    #[cfg(feature = "tempfile")]
+    pub fn benchmark_checksums(sample_size: u64) -> Result<Vec<(ChecksumAlgorithm, Duration)>> {
+        ChecksumAlgorithm::ALL
+            .into_iter()
+            .map(|checksum| {
+                let image = crate::temp::TempFile::new(sample_size)?;
+                let formatter = Formatter::options().checksum(checksum)?.build();
+                let timed = formatter.format_timed(&image)?;
+                if !timed.output.status.success() {
+                    return Err(formatter.failure(&timed.output));
+                }
+                Ok((checksum, timed.elapsed))
+            })
+            .collect()
+    }
+    /// Format a device with mkfs.btrfs, measuring how long it took
+    ///
+    /// # Examples
+    /// ```no_run
+    /// # use mkfs_btrfs_rs::Error;
+    /// use mkfs_btrfs_rs::format::*;
+    /// let timed = Formatter::options()
+    ///     .checksum(ChecksumAlgorithm::Sha256)?
+    ///     .build()
+    ///     .format_timed("./test.btrfs")?;
+    /// println!("sha256 took {:?}", timed.elapsed);
+    /// # Ok::<(), Error>(())
+    /// ```
+    pub fn format_timed<P: AsRef<Path>>(&self, device: P) -> Result<TimedOutput> {
+        let mut command = self.prepare(device)?;
+        let start = Instant::now();
+        let output = self.output(&mut command)?;
+        Ok(TimedOutput {
+            output,
+            elapsed: start.elapsed(),
+        })
+    }
+    /// Format a device with mkfs.btrfs, retrying while the device is busy
+    ///
+    /// Block devices can be briefly busy, e.g. right after partitioning. mkfs.btrfs is run
+    /// up to `attempts` times (at least once), waiting `backoff` before the first retry and
+    /// twice as long before each one after that. Failures that aren't `EBUSY` aren't retried.
+    ///
+    /// Returns the output of the last attempt.
+    ///
+    /// # Examples
+    /// ```no_run
+    /// # use mkfs_btrfs_rs::Error;
+    /// use mkfs_btrfs_rs::format::*;
+    /// use std::time::Duration;
+    /// Formatter::options()
+    ///     .build()
+    ///     .format_retry("/dev/sdb1", 5, Duration::from_millis(200))?;
+    /// # Ok::<(), Error>(())
+    /// ```
+    pub fn format_retry<P: AsRef<Path>>(
+        &self,
+        device: P,
+        attempts: u32,
+        backoff: Duration,
+    ) -> Result<Output> {
+        let device = device.as_ref();
+        let mut delay = backoff;
+        for _ in 1..attempts {
+            let output = self.format(device)?;
+            if output.status.success() || !is_busy(&output) {
+                return Ok(output);
+            }
+            thread::sleep(delay);
+            delay = delay.saturating_mul(2);
+        }
+        self.format(device)
+    }
+    /// Format a device with mkfs.btrfs, killing it if it takes longer than `timeout`
+    ///
+    /// Fails with [`Timeout`] if the timeout expires.
+    ///
+    /// # Examples
+    /// ```no_run
+    /// # use mkfs_btrfs_rs::Error;
+    /// use mkfs_btrfs_rs::format::*;
+    /// use std::time::Duration;
+    /// Formatter::options()
+    ///     .build()
+    ///     .format_timeout("./test.btrfs", Duration::from_secs(60))?;
+    /// # Ok::<(), Error>(())
+    /// ```
+    pub fn format_timeout<P: AsRef<Path>>(&self, device: P, timeout: Duration) -> Result<Output> {
+        let start = Instant::now();
+        self.format_until(device, || {
+            (start.elapsed() >= timeout).then_some(Timeout(timeout))
+        })
+    }
+    /// Format a device with mkfs.btrfs, killing it if `token` is cancelled first
+    ///
+    /// Fails with [`Cancelled`] if the token is cancelled.
+    /// To use this from an async runtime, run it on a blocking thread
+    /// and cancel the token from the async side.
+    ///
+    /// # Examples
+    /// ```no_run
+    /// # use mkfs_btrfs_rs::Error;
+    /// use mkfs_btrfs_rs::format::*;
+    /// let token = CancellationToken::new();
+    /// let canceller = token.clone();
+    /// std::thread::spawn(move || {
+    ///     std::thread::sleep(std::time::Duration::from_secs(60));
+    ///     canceller.cancel();
+    /// });
+    /// Formatter::options()
+    ///     .build()
+    ///     .format_cancellable("./test.btrfs", &token)?;
+    /// # Ok::<(), Error>(())
+    /// ```
+    pub fn format_cancellable<P: AsRef<Path>>(
+        &self,
+        device: P,
+        token: &CancellationToken,
+    ) -> Result<Output> {
+        self.format_until(device, || token.is_cancelled().then_some(Cancelled))
+    }
+    /// Format a device with mkfs.btrfs, killing it as soon as `stop` returns an error
+    fn format_until<P, F>(&self, device: P, stop: F) -> Result<Output>
+    where
+        P: AsRef<Path>,
+        F: Fn() -> Option<crate::Error>,
+    {
+        let mut command = self.prepare(device)?;
+        let log = self.open_log()?;
+        let mut child =
+            self.spawn_command(command.stdout(Stdio::piped()).stderr(Stdio::piped()))?;
+        // Drain both pipes while waiting, so a chatty child can't fill them and stall
+        let stdout = drain(child.stdout.take());
+        let stderr = drain(child.stderr.take());
+        let status = loop {
+            if let Some(status) = child.try_wait()? {
+                break status;
+            }
+            if let Some(err) = stop() {
+                child.kill()?;
+                child.wait()?;
+                return Err(err);
+            }
+            thread::sleep(Duration::from_millis(10));
+        };
+        let output = Output {
+            status,
+            stdout: join(stdout)?,
+            stderr: join(stderr)?,
+        };
+        write_log(log, &output)?;
+        Ok(output)
+    }
+    /// Format a device with mkfs.btrfs, passing each line of its output to a callback as it arrives
+    ///
+    /// Lines are passed without their trailing newline.
+    ///
+    /// # Examples
+    /// ```no_run
+    /// # use mkfs_btrfs_rs::Error;
+    /// use mkfs_btrfs_rs::format::*;
+    /// let status = Formatter::options()
+    ///     .build()
+    ///     .format_with_callbacks(
+    ///         "./test.btrfs",
+    ///         |line| println!("> {line}"),
+    ///         |line| eprintln!("! {line}"),
+    ///     )?;
+    /// # Ok::<(), Error>(())
+    /// ```
+    pub fn format_with_callbacks<P, O, E>(
+        &self,
+        device: P,
+        mut on_stdout: O,
+        mut on_stderr: E,
+    ) -> Result<ExitStatus>
+    where
+        P: AsRef<Path>,
+        O: FnMut(&str),
+        E: FnMut(&str),
+    {
+        let mut child = self.spawn_command(
+            self.prepare(device)?
+                .stdout(Stdio::piped())
+                .stderr(Stdio::piped()),
+        )?;
+        let (sender, receiver) = mpsc::channel();
+        let stdout = forward_lines(child.stdout.take(), sender.clone(), Line::Stdout);
+        let stderr = forward_lines(child.stderr.take(), sender, Line::Stderr);
+        // Both pipes are read on their own threads, so neither one can fill up and stall
+        for line in receiver {
+            match line {
+                Line::Stdout(line) => on_stdout(&line),
+                Line::Stderr(line) => on_stderr(&line),
+            }
+        }
+        join(stdout)?;
+        join(stderr)?;
+        Ok(child.wait()?)
+    }
+    /// Check `device` as [`format`](Self::format) would, but return the command instead of running it
+    ///
+    /// The command is quoted for a POSIX shell. Fails if the device doesn't exist, is a directory,
+    /// or is the root device, so everything but the formatting itself can be checked up front.
+    ///
+    /// # Examples
+    /// ```no_run
+    /// # use mkfs_btrfs_rs::Error;
+    /// use mkfs_btrfs_rs::format::*;
+    /// let preview = Formatter::options()
+    ///     .label("My Volume")?
+    ///     .build()
+    ///     .format_preview("./test.btrfs")?;
+    /// assert_eq!("mkfs.btrfs '--label=My Volume' ./test.btrfs", preview);
+    /// # Ok::<(), Error>(())
+    /// ```
+    pub fn format_preview<P: AsRef<Path>>(&self, device: P) -> Result<String> {
+        let command = self.command(device)?;
+        let words: Vec<String> = std::iter::once(command.get_program())
+            .chain(command.get_args())
+            .map(|word| shell_quote(&word.to_string_lossy()))
+            .collect();
+        Ok(words.join(" "))
+    }
+    /// Format a device with mkfs.btrfs, sorting the result by whether mkfs.btrfs succeeded
+    ///
+    /// Still fails if mkfs.btrfs can't be run at all, but a failing mkfs.btrfs
+    /// is a [`FormatOutcome::Failed`], not an error, with its full output.
+    ///
+    /// # Examples
+    /// ```no_run
+    /// # use mkfs_btrfs_rs::Error;
+    /// use mkfs_btrfs_rs::format::*;
+    /// match Formatter::options().build().format_outcome("./test.btrfs")? {
+    ///     FormatOutcome::Success(output) => println!("{}", output.stdout_str()),
+    ///     FormatOutcome::Failed { status, output } => {
+    ///         eprintln!("mkfs.btrfs exited with {status}:\n{}", output.stderr_str())
+    ///     }
+    /// }
+    /// # Ok::<(), Error>(())
+    /// ```
+    pub fn format_outcome<P: AsRef<Path>>(&self, device: P) -> Result<FormatOutcome> {
+        let output = self.format(device)?;
+        Ok(if output.status.success() {
+            FormatOutcome::Success(output)
+        } else {
+            FormatOutcome::Failed {
+                status: output.status,
+                output,
+            }
+        })
+    }
+    /// Format a device with mkfs.btrfs, and return its path, ready to be mounted
+    ///
+    /// Relative paths are resolved against the [`base_dir`](FormatterOptions::base_dir), if set.
+    /// Fails with [`CommandFailed`] if mkfs.btrfs does.
+    ///
+    /// # Examples
+    /// ```no_run
+    /// # use mkfs_btrfs_rs::Error;
+    /// use mkfs_btrfs_rs::format::*;
+    /// let device = Formatter::options()
+    ///     .build()
+    ///     .format_path("./test.btrfs")?;
+    /// println!("mount {} /mnt", device.display());
+    /// # Ok::<(), Error>(())
+    /// ```
+    pub fn format_path<P: AsRef<Path>>(&self, device: P) -> Result<PathBuf> {
+        let device = self.values.resolve(device.as_ref()).into_owned();
+        let output = self.format(&device)?;
+        if !output.status.success() {
+            return Err(self.failure(&output));
+        }
+        Ok(device)
+    }
+    /// Format a device with mkfs.btrfs, then check the new filesystem with `btrfs check --readonly`
+    ///
+    /// Fails like [`format_path`](Self::format_path) if formatting fails,
+    /// or with [`PostCheckFailed`] if `btrfs check` finds a problem.
+    ///
+    /// # Examples
+    /// ```no_run
+    /// # use mkfs_btrfs_rs::Error;
+    /// use mkfs_btrfs_rs::format::*;
+    /// let output = Formatter::options()
+    ///     .build()
+    ///     .format_verified("./test.btrfs")?;
+    /// # Ok::<(), Error>(())
+    /// ```
+    pub fn format_verified<P: AsRef<Path>>(&self, device: P) -> Result<Output> {
+        let device = self.values.resolve(device.as_ref());
+        let output = self.format(&device)?;
+        if !output.status.success() {
+            return Err(self.failure(&output));
+        }
+        let check = Command::new(&self.check_binary)
+            .args(["check", "--readonly"])
+            .arg(device.as_ref())
+            .stdin(Stdio::null())
+            .output()?;
+        if !check.status.success() {
+            return Err(PostCheckFailed {
+                status: check.status,
+                stderr: check.stderr_str().into_owned(),
+            });
+        }
+        Ok(output)
+    }
+    /// Format a device with mkfs.btrfs, then make sure the new filesystem uses the requested checksum
+    ///
+    /// Reads the checksum back with `btrfs inspect-internal dump-super`, using the
+    /// [`check binary`](Self::with_check_binary). Without a [`checksum`](FormatterOptions::checksum),
+    /// mkfs.btrfs' default of crc32c is expected. Fails with [`ChecksumMismatch`] if they differ,
+    /// [`ReportError`] if the checksum can't be read back, or like [`format_path`](Self::format_path)
+    /// if formatting fails.
+    ///
+    /// # Examples
+    /// ```no_run
+    /// # use mkfs_btrfs_rs::Error;
+    /// use mkfs_btrfs_rs::format::*;
+    /// Formatter::options()
+    ///     .checksum(ChecksumAlgorithm::XxHash)?
+    ///     .build()
+    ///     .format_verified_checksum("./test.btrfs")?;
+    /// # Ok::<(), Error>(())
+    /// ```
+    pub fn format_verified_checksum<P: AsRef<Path>>(&self, device: P) -> Result<Output> {
+        let device = self.values.resolve(device.as_ref());
+        let output = self.format(&device)?;
+        if !output.status.success() {
+            return Err(self.failure(&output));
+        }
+        let dump = Command::new(&self.check_binary)
+            .args(["inspect-internal", "dump-super"])
+            .arg(device.as_ref())
+            .env("LC_ALL", "C")
+            .stdin(Stdio::null())
+            .output()?;
+        if !dump.status.success() {
+            return Err(ReportError(format!(
+                "btrfs inspect-internal dump-super exited with {}\n> STDERR:\n{}",
+                dump.status,
+                dump.stderr_str()
+            )));
+        }
+        let found = report::parse_csum_type(&dump.stdout_str()).ok_or_else(|| {
+            ReportError(String::from(
+                "btrfs inspect-internal dump-super did not report a csum_type",
+            ))
+        })?;
+        let expected = self.values.checksum.unwrap_or_default();
+        if found != expected {
+            return Err(ChecksumMismatch { expected, found });
+        }
+        Ok(output)
+    }
+    /// Format several devices, one after the other, with the same options
+    ///
+    /// Each device is formatted independently: a failure doesn't stop the rest from being formatted.
+    /// A [`device_uuid`](FormatterOptions::device_uuid) can only belong to one device,
+    /// so if one is set with more than one device, nothing is formatted
+    /// and every device fails with [`ArgumentError`].
+    ///
+    /// # Examples
+    /// ```no_run
+    /// # use mkfs_btrfs_rs::Error;
+    /// use mkfs_btrfs_rs::format::*;
+    /// let results = Formatter::options()
+    ///     .build()
+    ///     .format_batch(&["./a.btrfs", "./b.btrfs"]);
+    /// for (device, result) in results {
+    ///     println!("{}: {}", device.display(), result.is_ok());
+    /// }
+    /// # Ok::<(), Error>(())
+    /// ```
+    pub fn format_batch<P: AsRef<Path>>(&self, devices: &[P]) -> Vec<(PathBuf, Result<Output>)> {
+        let shared_uuid = self
+            .values
+            .device_uuid
+            .as_ref()
+            .filter(|_| devices.len() > 1);
+        devices
+            .iter()
+            .map(|device| {
+                let result = match shared_uuid {
+                    Some(uuid) => Err(rejected(
+                        "device_uuid",
+                        uuid,
+                        format!(
+                            "device_uuid ( = {uuid} )\nMust not be shared by {} devices; each device needs its own",
+                            devices.len()
+                        ),
+                    )),
+                    None => self.format(device),
+                };
+                (device.as_ref().to_owned(), result)
+            })
+            .collect()
+    }
+
+    /// Format a device with mkfs.btrfs, and parse its output into a [`FormatReport`]
+    ///
+    /// Fails with [`CommandFailed`] if mkfs.btrfs does, [`ExperimentalProfile`]
+    /// if it refused a RAID5/6 profile, or [`UnsupportedOption`] if it's too old for an option.
+    /// If `free-space-tree` was requested, as a feature or a runtime feature,
+    /// but isn't in the report, fails with [`ReportError`].
+    ///
+    /// # Examples
+    /// ```no_run
+    /// # use mkfs_btrfs_rs::Error;
+    /// use mkfs_btrfs_rs::format::*;
+    /// let report = Formatter::options()
+    ///     .build()
+    ///     .format_reported("./test.btrfs")?;
+    /// # Ok::<(), Error>(())
+    /// ```
+    pub fn format_reported<P: AsRef<Path>>(&self, device: P) -> Result<FormatReport> {
+        self.reported(device.as_ref(), |device| self.format(device))
+    }
+    /// Format a device with mkfs.btrfs, returning both its [`FormatReport`] and how long it ran
+    ///
+    /// Fails the same way as [`format_reported`](Self::format_reported).
+    /// The duration is measured like [`format_timed`](Self::format_timed)'s.
+    ///
+    /// # Examples
+    /// ```no_run
+    /// # use mkfs_btrfs_rs::Error;
+    /// use mkfs_btrfs_rs::format::*;
+    /// let (report, elapsed) = Formatter::options()
+    ///     .build()
+    ///     .format_full("./test.btrfs")?;
+    /// println!("{} devices in {elapsed:?}", report.device_count());
+    /// # Ok::<(), Error>(())
+    /// ```
+    pub fn format_full<P: AsRef<Path>>(&self, device: P) -> Result<(FormatReport, Duration)> {
+        let mut elapsed = Duration::ZERO;
+        let report = self.reported(device.as_ref(), |device| {
+            let timed = self.format_timed(device)?;
+            elapsed = timed.elapsed;
+            Ok(timed.output)
+        })?;
+        Ok((report, elapsed))
+    }
+    /// Run mkfs.btrfs on a device with `run`, then parse and check its report
+    fn reported<F>(&self, device: &Path, run: F) -> Result<FormatReport>
+    where
+        F: FnOnce(&Path) -> Result<Output>,
+    {
+        let device = self.values.resolve(device);
+        let device_size = if self.values.fill_device {
+            Some(device::device_size(&device)?)
+        } else {
+            None
+        };
+        let output = run(&device)?;
+        if !output.status.success() {
+            return Err(self.failure(&output));
+        }
+        let mut report = FormatReport::parse(&output.stdout_str());
+        report.device_size_bytes = device_size;
+        let free_space_tree = self
+            .args
+            .iter()
+            .filter(|arg| {
+                // `--features` since mkfs.btrfs 5.15, `--runtime-features` before
+                arg.to_str().is_some_and(|arg| {
+                    arg.starts_with("--features=") || arg.starts_with("--runtime-features=")
+                })
+            })
+            .filter_map(arg_value)
+            .any(|features| features.split(',').any(|f| f == "free-space-tree"));
+        if free_space_tree && !report.free_space_tree {
+            return Err(ReportError(String::from(
+                "free-space-tree was requested, but mkfs.btrfs didn't report enabling it",
+            )));
+        }
+        Ok(report)
+    }
+    /// Format a device with `rootdir` and `shrink`, returning the size (in bytes)
+    /// the filesystem was shrunk to.
+    ///
+    /// # Examples
+    /// ```no_run
+    /// # use mkfs_btrfs_rs::Error;
+    /// use mkfs_btrfs_rs::format::*;
+    /// let bytes = Formatter::options()
+    ///     .rootdir("./src")?
+    ///     .shrink()?
+    ///     .build()
+    ///     .format_shrunk("./test.btrfs")?;
+    /// # Ok::<(), Error>(())
+    /// ```
+    pub fn format_shrunk<P: AsRef<Path>>(&self, device: P) -> Result<u64> {
+        if !self.args.contains(&OsString::from("--shrink")) {
+            return Err(ArgumentError {
+                option: "shrink",
+                message: String::from("format_shrunk requires the `shrink` option to be set"),
+            });
+        }
+        self.format_reported(device)?
+            .shrunk_size_bytes
+            .ok_or_else(|| ReportError(String::from("mkfs.btrfs did not report a shrunk size")))
+    }
+
+    /// Turn the output of a failed mkfs.btrfs into the most specific error
+    fn failure(&self, output: &Output) -> crate::Error {
+        let stderr = output.stderr_str();
+        let lowercase = stderr.to_lowercase();
+        let raid56 = ["raid5", "raid6", "raid56"]
+            .iter()
+            .any(|profile| lowercase.contains(profile));
+        let refused = ["experimental", "not supported", "unsupported"]
+            .iter()
+            .any(|reason| lowercase.contains(reason));
+        if raid56 && refused {
+            let profile = [self.values.data, self.values.metadata]
+                .into_iter()
+                .flatten()
+                .find(|profile| matches!(profile, DataProfile::Raid5 | DataProfile::Raid6))
+                .unwrap_or(if lowercase.contains("raid6") {
+                    DataProfile::Raid6
+                } else {
+                    DataProfile::Raid5
+                });
+            return ExperimentalProfile { profile };
+        }
+        if let Some(option) = unrecognized_option(&stderr) {
+            return UnsupportedOption { option };
+        }
+        CommandFailed {
+            status: output.status,
+            stderr: stderr.into_owned(),
+        }
+    }
+
+    /// Check whether formatting a device would overwrite an existing filesystem
+    ///
+    /// Only reads the device, looking for the same magic numbers as [`preflight`](Self::preflight).
+    /// mkfs.btrfs refuses to overwrite a filesystem unless [`force`](FormatterOptions::force) is set,
+    /// so use this to decide whether to ask first.
+    ///
+    /// # Examples
+    /// ```no_run
+    /// # use mkfs_btrfs_rs::Error;
+    /// use mkfs_btrfs_rs::format::*;
+    /// let formatter = Formatter::options().build();
+    /// if !formatter.would_clobber("./test.btrfs")? {
+    ///     formatter.format("./test.btrfs")?;
+    /// }
+    /// # Ok::<(), Error>(())
+    /// ```
+    pub fn would_clobber<P: AsRef<Path>>(&self, device: P) -> Result<bool> {
+        let device = self.values.resolve(device.as_ref());
+        Ok(device::probe_filesystem(&device)?.is_some())
+    }
+    /// Format a device, unless it already holds a btrfs filesystem
+    ///
+    /// Returns whether it was formatted, so provisioning can be re-run safely.
+    /// Only the superblock magic is checked, not whether the existing filesystem
+    /// matches these options. Any other filesystem is formatted over, which mkfs.btrfs
+    /// refuses unless [`force`](FormatterOptions::force) is set. Fails like
+    /// [`format_path`](Self::format_path) if formatting fails.
+    ///
+    /// # Examples
+    /// ```no_run
+    /// # use mkfs_btrfs_rs::Error;
+    /// use mkfs_btrfs_rs::format::*;
+    /// let formatted = Formatter::options()
+    ///     .label("data")?
+    ///     .build()
+    ///     .ensure_formatted("/dev/sdb1")?;
+    /// println!("{}", if formatted { "formatted" } else { "already btrfs" });
+    /// # Ok::<(), Error>(())
+    /// ```
+    pub fn ensure_formatted<P: AsRef<Path>>(&self, device: P) -> Result<bool> {
+        let device = self.values.resolve(device.as_ref());
+        self.command(&device)?;
+        if device::probe_filesystem(&device)? == Some("btrfs") {
+            return Ok(false);
+        }
+        let output = self.format(&device)?;
+        if !output.status.success() {
+            return Err(self.failure(&output));
+        }
+        Ok(true)
+    }
+    /// Describe what formatting a device would do, without formatting it
+    ///
+    /// The device is checked the same way as in [`format`](Self::format), then probed
+    /// for the magic number of an existing btrfs, ext2/3/4, xfs or FAT32 filesystem.
+    ///
+    /// # Examples
+    /// ```no_run
+    /// # use mkfs_btrfs_rs::Error;
+    /// use mkfs_btrfs_rs::format::*;
+    /// let preflight = Formatter::options()
+    ///     .build()
+    ///     .preflight("/dev/sdb1")?;
+    /// if let Some(filesystem) = preflight.existing_filesystem {
+    ///     println!("this will erase the {filesystem} filesystem on /dev/sdb1");
+    /// }
+    /// # Ok::<(), Error>(())
+    /// ```
+    pub fn preflight<P: AsRef<Path>>(&self, device: P) -> Result<Preflight> {
+        let device = self.values.resolve(device.as_ref()).into_owned();
+        self.command(&device)?;
+        let metadata = device.metadata()?;
+        let kind = if metadata.is_file() {
+            TargetKind::File
+        } else if device::is_block_device(&metadata) {
+            TargetKind::BlockDevice
+        } else {
+            TargetKind::Other
+        };
+        Ok(Preflight {
+            existing_filesystem: device::probe_filesystem(&device)?,
+            warnings: self.warnings(),
+            force: self.args.contains(&OsString::from("--force")),
+            device,
+            kind,
+        })
+    }
+    /// Collect the warnings for [`preflight`](Self::preflight), from every check that isn't ignored
+    fn warnings(&self) -> Vec<String> {
+        let mut warnings = vec![];
+        if self.values.page_size_check != Strictness::Ignore {
+            warnings.extend(
+                self.values
+                    .page_size_mismatch()
+                    .map(|violation| violation.to_string()),
+            );
+        }
+        let shrink = self.args.iter().any(|arg| arg == "--shrink");
+        let rootdir = self.args.iter().any(|arg| {
+            arg.to_str()
+                .is_some_and(|arg| arg.starts_with("--rootdir="))
+        });
+        if self.values.shrink_check() != Strictness::Ignore && shrink && !rootdir {
+            warnings.push(String::from(SHRINK_WITHOUT_ROOTDIR));
+        }
+        if self.values.checksum_check != Strictness::Ignore {
+            if let Some((checksum, sectorsize)) = self.values.heavy_checksum() {
+                warnings.push(
+                    Violation::HeavyChecksum {
+                        checksum,
+                        sectorsize,
+                    }
+                    .to_string(),
+                );
+            }
+        }
+        warnings
+    }
+    /// Set up, but don't spawn, the Command that would format a device
+    ///
+    /// Use this to set the environment, working directory or stdio yourself.
+    /// The device is checked the same way as in [`format`](Self::format).
+    ///
+    /// # Examples
+    /// ```no_run
+    /// # use mkfs_btrfs_rs::Error;
+    /// use mkfs_btrfs_rs::format::*;
+    /// let mut command = Formatter::options()
+    ///     .label("my-Btrfs-volume")?
+    ///     .build()
+    ///     .into_command("./test.btrfs")?;
+    /// command.current_dir("/tmp").status()?;
+    /// # Ok::<(), Error>(())
+    /// ```
+    pub fn into_command<P: AsRef<Path>>(self, device: P) -> Result<Command> {
+        self.command(device)
+    }
+
+    /// Set up a Command to format the device, like [`command`](Self::command),
+    /// then wipe it if asked to: the last step before mkfs.btrfs runs
+    fn prepare<P: AsRef<Path>>(&self, device: P) -> Result<Command> {
+        let command = self.command(&device)?;
+        if self.values.wipe_signatures {
+            device::wipe_signatures(&self.values.resolve(device.as_ref()))?;
+        }
+        Ok(command)
+    }
+
+    /// Make sure the device exists, then set up a Command to format it
+    ///
+    /// The device is appended to the command, not `self.args`,
+    /// so the same Formatter can format any number of devices.
+    fn command<P: AsRef<Path>>(&self, device: P) -> Result<Command> {
+        if cfg!(not(target_os = "linux")) {
+            return Err(UnsupportedPlatform(std::env::consts::OS));
+        }
+        let device = self.values.resolve(device.as_ref());
+        let device = device.as_ref();
+        if self.skip_existence_check {
+            // Still refuse to clobber `/`, if the device can be inspected at all
+            if let Ok(true) = device.try_exists() {
+                self.check_device(device)?;
+            }
+        } else {
+            if !device.try_exists()? {
+                return Err(ArgumentError {
+                    option: "device",
+                    message: format!(
+                        "target path does not exist: {}; create the file or device first",
+                        device.display()
+                    ),
+                });
+            }
+            self.check_device(device)?;
+        }
+        let mut command = Command::new(&self.binary);
+        if !self.values.native_locale {
+            // Keep messages in English, so they can be parsed
+            command.env("LC_ALL", "C").env("LANG", "C");
+        }
+        command
+            .envs(self.values.env.iter().map(|(key, value)| (key, value)))
+            .args(&self.args);
+        if self.values.auto_discard
+            && !self.args.iter().any(|arg| arg == "--nodiscard")
+            // A device that isn't there yet, or can't be probed, may well be an SSD
+            && matches!(device.try_exists(), Ok(true))
+            && matches!(device::is_rotational(device), Ok(Some(true)))
+        {
+            command.arg("--nodiscard");
+        }
+        command.arg(device).stdin(if self.stdin.is_some() {
+            Stdio::piped()
+        } else {
+            Stdio::null()
+        });
+        Ok(command)
+    }
+
+    /// Run a command to completion, collecting its output
+    fn output(&self, command: &mut Command) -> IoResult<Output> {
+        let log = self.open_log()?;
+        let output = self
+            .spawn_command(command.stdout(Stdio::piped()).stderr(Stdio::piped()))?
+            .wait_with_output()?;
+        write_log(log, &output)?;
+        Ok(output)
+    }
+
+    /// Open the [`log_to`](FormatterOptions::log_to) file for appending, if there is one
+    fn open_log(&self) -> IoResult<Option<File>> {
+        self.values
+            .log_to
+            .as_ref()
+            .map(|path| OpenOptions::new().create(true).append(true).open(path))
+            .transpose()
+    }
+
+    /// Spawn a command, feeding it [`with_stdin`](Self::with_stdin)'s input on another thread
+    fn spawn_command(&self, command: &mut Command) -> IoResult<Child> {
+        let mut child = command.spawn()?;
+        if let (Some(mut pipe), Some(input)) = (child.stdin.take(), self.stdin.clone()) {
+            thread::spawn(move || {
+                // mkfs.btrfs doesn't have to read all of it
+                let _ = pipe.write_all(&input);
+            });
+        }
+        Ok(child)
+    }
+
+    /// Make sure the device is safe to format, and can hold the filesystem
+    fn check_device(&self, device: &Path) -> Result<()> {
+        if !self.values.allow_dangerous && device::backs_root(device)? {
+            return Err(RootDevice(device.to_owned()));
+        }
+        let metadata = device.metadata()?;
+        if metadata.is_dir() {
+            return Err(ArgumentError {
+                option: "device",
+                message: format!(
+                    "{} is a directory; mkfs.btrfs needs a file or block device",
+                    device.display()
+                ),
+            });
+        }
+        // Block devices report a length of 0, so only files can be checked
+        if let (true, Some(byte_count)) = (metadata.is_file(), self.values.byte_count) {
+            if byte_count > metadata.len() {
+                return Err(ArgumentError {
+                    option: "byte_count",
+                    message: format!(
+                        "byte_count ( = {byte_count} )\nMust not exceed the size of {} ( = {} )",
+                        device.display(),
+                        metadata.len()
+                    ),
+                });
+            }
+        }
+        Ok(())
+    }
+}
+
+/// The [`Output`] of mkfs.btrfs, and how long it took to run.
+#[derive(Clone, Debug, PartialEq, Eq)]
+pub struct TimedOutput {
+    pub output: Output,
+    /// Wall-clock time mkfs.btrfs ran for
+    pub elapsed: Duration,
+}
+
+/// Whether mkfs.btrfs succeeded, from [`Formatter::format_outcome`]
+#[derive(Clone, Debug, PartialEq, Eq)]
+pub enum FormatOutcome {
+    /// mkfs.btrfs exited successfully
+    Success(Output),
+    /// mkfs.btrfs exited with a failing `status`
+    Failed { output: Output, status: ExitStatus },
+}
+
+impl FormatOutcome {
+    /// The output of mkfs.btrfs, whether it succeeded or not
+    pub fn output(&self) -> &Output {
+        match self {
+            FormatOutcome::Success(output) | FormatOutcome::Failed { output, .. } => output,
+        }
+    }
+}
+
+/// What [`Formatter::rootdir_stats`] found in a directory
+#[derive(Clone, Debug, Default, PartialEq, Eq)]
+pub struct RootdirStats {
+    /// Everything that isn't a directory: files, symlinks, devices and so on.
+    /// Each hardlink counts separately.
+    pub file_count: u64,
+    /// The size of the files, counting hardlinked files once
+    pub total_bytes: u64,
+    /// Paths, relative to the directory, that are hardlinks to the same file.
+    /// Each group has at least two paths, and is sorted.
+    pub hardlinks: Vec<Vec<PathBuf>>,
+}
+
+/// What [`Formatter::preflight`] found out about a device before formatting it.
+#[derive(Clone, Debug, PartialEq, Eq)]
+pub struct Preflight {
+    /// The path that would be formatted
+    pub device: PathBuf,
+    /// Whether it's a file or a block device
+    pub kind: TargetKind,
+    /// The filesystem already on the device, which formatting would destroy
+    pub existing_filesystem: Option<&'static str>,
+    /// Whether `--force` is set, so mkfs.btrfs would overwrite an existing filesystem
+    pub force: bool,
+    /// Problems that won't stop mkfs.btrfs, but may make the filesystem hard to use
+    pub warnings: Vec<String>,
+}
+
+/// The configuration of a Formatter, from [`Formatter::describe`]
+///
+/// `None` means the option isn't set, and mkfs.btrfs picks its default.
+/// Displays as one `name: value` line per option that's set.
+#[derive(Clone, Debug, Default, PartialEq, Eq)]
+pub struct FormatterSummary {
+    pub data: Option<DataProfile>,
+    pub metadata: Option<DataProfile>,
+    pub checksum: Option<ChecksumAlgorithm>,
+    /// The size of the filesystem, in bytes
+    pub byte_count: Option<u64>,
+    pub nodesize: Option<usize>,
+    pub sectorsize: Option<usize>,
+    /// Features to set, and unset ones prefixed with `^`
+    pub features: Vec<String>,
+}
+
+impl std::fmt::Display for FormatterSummary {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        fn line(
+            f: &mut std::fmt::Formatter<'_>,
+            name: &str,
+            value: Option<impl std::fmt::Display>,
+        ) -> std::fmt::Result {
+            match value {
+                Some(value) => writeln!(f, "{name}: {value}"),
+                None => Ok(()),
+            }
+        }
+        line(f, "data", self.data)?;
+        line(f, "metadata", self.metadata)?;
+        line(f, "checksum", self.checksum)?;
+        line(f, "byte_count", self.byte_count)?;
+        line(f, "nodesize", self.nodesize)?;
+        line(f, "sectorsize", self.sectorsize)?;
+        line(
+            f,
+            "features",
+            (!self.features.is_empty()).then(|| self.features.join(",")),
+        )
+    }
+}
+
+/// The kind of thing a [`Preflight`] would format.
+#[derive(Clone, Copy, Debug, PartialEq, Eq, Hash)]
+pub enum TargetKind {
+    File,
+    BlockDevice,
+    /// Anything else, like a character device
+    Other,
+}
+
+/// Decode the output of mkfs.btrfs, replacing invalid UTF-8 with `�`
+///
+/// # Examples
+/// ```no_run
+/// # use mkfs_btrfs_rs::Error;
+/// use mkfs_btrfs_rs::format::*;
+/// let output = Formatter::options()
+///     .build()
+///     .format("./test.btrfs")?;
+/// println!("{}", output.stdout_str());
+/// # Ok::<(), Error>(())
+/// ```
+pub trait OutputExt {
+    /// The captured stdout, as text
+    fn stdout_str(&self) -> Cow<'_, str>;
+    /// The captured stderr, as text
+    fn stderr_str(&self) -> Cow<'_, str>;
+}
+
+impl OutputExt for Output {
+    fn stdout_str(&self) -> Cow<'_, str> {
+        String::from_utf8_lossy(&self.stdout)
+    }
+    fn stderr_str(&self) -> Cow<'_, str> {
+        String::from_utf8_lossy(&self.stderr)
+    }
+}
+
+/// Cancels a [`Formatter::format_cancellable`] from another thread.
+///
+/// Clones share the same state, so cancelling any of them cancels them all.
+#[derive(Clone, Debug, Default)]
+pub struct CancellationToken {
+    cancelled: Arc<AtomicBool>,
+}
+
+impl CancellationToken {
+    pub fn new() -> Self {
+        Self::default()
+    }
+    /// Cancel any formats waiting on this token
+    pub fn cancel(&self) {
+        self.cancelled.store(true, Ordering::SeqCst);
+    }
+    pub fn is_cancelled(&self) -> bool {
+        self.cancelled.load(Ordering::SeqCst)
+    }
+}
+
+/// Whether mkfs.btrfs failed because the device was busy
+fn is_busy(output: &Output) -> bool {
+    let stderr = output.stderr_str().to_lowercase();
+    stderr.contains("device or resource busy") || stderr.contains("ebusy")
+}
+
+/// Read a child's pipe to the end on another thread
+fn drain<R: Read + Send + 'static>(pipe: Option<R>) -> JoinHandle<IoResult<Vec<u8>>> {
+    thread::spawn(move || {
+        let mut buf = vec![];
+        if let Some(mut pipe) = pipe {
+            pipe.read_to_end(&mut buf)?;
+        }
+        Ok(buf)
+    })
+}
+
+/// A line of output from a child, and which pipe it came from
+enum Line {
+    Stdout(String),
+    Stderr(String),
+}
+
+/// Send each line of a child's pipe through `sender`, on another thread
+fn forward_lines<R: Read + Send + 'static>(
+    pipe: Option<R>,
+    sender: Sender<Line>,
+    wrap: fn(String) -> Line,
+) -> JoinHandle<IoResult<()>> {
+    thread::spawn(move || {
+        let Some(pipe) = pipe else { return Ok(()) };
+        let mut pipe = BufReader::new(pipe);
+        let mut buf = vec![];
+        while pipe.read_until(b'\n', &mut buf)? > 0 {
+            let line = String::from_utf8_lossy(&buf);
+            let line = line.strip_suffix('\n').unwrap_or(&line);
+            // The receiver only hangs up once we're done sending
+            let _ = sender.send(wrap(line.to_owned()));
+            buf.clear();
+        }
+        Ok(())
+    })
+}
+
+/// Wait for a pipe reader to finish
+fn join<T>(handle: JoinHandle<IoResult<T>>) -> IoResult<T> {
+    handle.join().expect("pipe reader panicked")
 }
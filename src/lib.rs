@@ -1,5 +1,6 @@
 #![doc = include_str!("../readme.md")]
 
+use std::{path::PathBuf, process::ExitStatus, time::Duration};
 use thiserror::Error;
 
 /// A specialized [`Result`] type for mkfs-btrfs-rs errors.
@@ -10,12 +11,57 @@ pub type Result<T> = std::result::Result<T, crate::Error>;
 pub enum Error {
     #[error("{0}")]
     IoError(#[from] std::io::Error),
+    #[error("{message}")]
+    ArgumentError {
+        /// The option that was rejected
+        option: &'static str,
+        message: String,
+    },
+    #[error("mkfs.btrfs exited with {status}\n> STDERR:\n{stderr}")]
+    CommandFailed { status: ExitStatus, stderr: String },
     #[error("{0}")]
-    ArgumentError(String),
+    ReportError(String),
+    #[error("asked for the {expected} checksum, but the new filesystem uses {found}")]
+    ChecksumMismatch {
+        expected: ChecksumAlgorithm,
+        found: ChecksumAlgorithm,
+    },
+    #[error("btrfs check found problems with the new filesystem, exiting with {status}\n> STDERR:\n{stderr}")]
+    PostCheckFailed { status: ExitStatus, stderr: String },
+    #[error(
+        "mkfs.btrfs refused the experimental {profile} profile\n\
+         RAID5/6 needs support from both the kernel and btrfs-progs; consider raid1, raid1c3 or raid10 instead"
+    )]
+    ExperimentalProfile { profile: DataProfile },
+    #[error("mkfs.btrfs does not recognize the option {option}; it may be too old")]
+    UnsupportedOption { option: String },
+    #[error("mkfs.btrfs timed out after {0:?}")]
+    Timeout(Duration),
+    #[error("mkfs.btrfs was cancelled")]
+    Cancelled,
+    #[error("mkfs.btrfs only runs on Linux, not {0}")]
+    UnsupportedPlatform(&'static str),
+    #[error("refusing to format {0}: it backs the root filesystem! Use `allow_dangerous()` if you really mean it")]
+    RootDevice(PathBuf),
 }
 
+mod device;
+mod random;
+
 pub mod format;
-pub use format::{ChecksumAlgorithm, DataProfile, Formatter};
+pub use format::{ChecksumAlgorithm, DataProfile, Field, Formatter, MkfsFeature, OutputExt};
+
+pub mod report;
+pub use report::{BlockGroup, DeviceInfo, FormatReport};
+
+pub mod validate;
+pub use validate::Violation;
+
+#[cfg(feature = "log")]
+pub mod log;
+
+#[cfg(feature = "tempfile")]
+pub mod temp;
 
 #[cfg(test)]
 mod tests;
@@ -0,0 +1,46 @@
+//! # Logging of rejected arguments
+//!
+//! Enabled by the `log` feature. Whenever a setter rejects a value with an
+//! [`ArgumentError`](crate::Error::ArgumentError), a [`Rejected`] record is handed
+//! to the hook installed with [`set_hook`], so it can be forwarded to whichever
+//! logger the application uses.
+//!
+//! # Examples
+//! ```
+//! use mkfs_btrfs_rs::{format::Formatter, log};
+//! log::set_hook(|rejected| eprintln!("{}: {}", rejected.option, rejected.value));
+//! assert!(Formatter::options().nodesize(3).is_err());
+//! ```
+
+use std::sync::RwLock;
+
+/// The hook records are handed to, if one is installed
+static HOOK: RwLock<Option<fn(&Rejected)>> = RwLock::new(None);
+
+/// A value that was rejected by a setter
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub struct Rejected<'a> {
+    /// The option that was rejected
+    pub option: &'static str,
+    /// The attempted value
+    pub value: &'a str,
+    /// Why it was rejected, as in the returned error
+    pub message: &'a str,
+}
+
+/// Install the hook that every [`Rejected`] record is handed to, replacing any earlier one
+pub fn set_hook(hook: fn(&Rejected)) {
+    *HOOK.write().unwrap_or_else(|e| e.into_inner()) = Some(hook);
+}
+
+/// Stop handing out records
+pub fn clear_hook() {
+    *HOOK.write().unwrap_or_else(|e| e.into_inner()) = None;
+}
+
+/// Hand a record to the hook, if there is one
+pub(crate) fn emit(rejected: &Rejected) {
+    if let Some(hook) = *HOOK.read().unwrap_or_else(|e| e.into_inner()) {
+        hook(rejected);
+    }
+}
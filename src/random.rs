@@ -0,0 +1,86 @@
+//! # Just enough randomness for labels and UUIDs
+//!
+//! Not cryptographically secure, and not meant to be.
+
+use std::{
+    collections::hash_map::RandomState,
+    hash::{BuildHasher, Hasher},
+    sync::atomic::{AtomicU64, Ordering},
+    time::{SystemTime, UNIX_EPOCH},
+};
+
+/// Mixed into every random number, so two calls in the same instant still differ
+static COUNTER: AtomicU64 = AtomicU64::new(0);
+
+/// Get a random u64, seeded by the OS through [`RandomState`]
+pub(crate) fn random_u64() -> u64 {
+    let mut hasher = RandomState::new().build_hasher();
+    hasher.write_u64(COUNTER.fetch_add(1, Ordering::Relaxed));
+    hasher.write_u128(unix_nanos());
+    hasher.finish()
+}
+
+/// A tiny seeded PRNG, for when the same seed must give the same numbers
+pub(crate) struct SplitMix64(u64);
+
+impl SplitMix64 {
+    pub(crate) fn new(seed: u64) -> Self {
+        Self(seed)
+    }
+
+    pub(crate) fn next_u64(&mut self) -> u64 {
+        self.0 = self.0.wrapping_add(0x9e37_79b9_7f4a_7c15);
+        let mut z = self.0;
+        z = (z ^ (z >> 30)).wrapping_mul(0xbf58_476d_1ce4_e5b9);
+        z = (z ^ (z >> 27)).wrapping_mul(0x94d0_49bb_1331_11eb);
+        z ^ (z >> 31)
+    }
+}
+
+/// Nanoseconds since the Unix epoch
+fn unix_nanos() -> u128 {
+    SystemTime::now()
+        .duration_since(UNIX_EPOCH)
+        .map(|time| time.as_nanos())
+        .unwrap_or_default()
+}
+
+/// Generate a random (version 4) UUID
+pub(crate) fn uuid_v4() -> String {
+    uuid_v4_from(random_u64(), random_u64())
+}
+
+/// Build a version 4 UUID out of 128 bits
+pub(crate) fn uuid_v4_from(high: u64, low: u64) -> String {
+    let high = (high & !0xf000) | 0x4000; // version 4
+    let low = (low & !(0b11 << 62)) | (0b10 << 62); // RFC 4122 variant
+    format_uuid(high, low)
+}
+
+/// Generate a time-based (version 1) UUID, with a random node ID
+pub(crate) fn uuid_v1() -> String {
+    // 100ns intervals since the start of the Gregorian calendar
+    let timestamp = (unix_nanos() / 100) as u64 + 0x01B2_1DD2_1381_4000;
+    let high = ((timestamp & 0xffff_ffff) << 32)
+        | (((timestamp >> 32) & 0xffff) << 16)
+        | ((timestamp >> 48) & 0x0fff)
+        | 0x1000; // version 1
+    let random = random_u64();
+    let clock_seq = ((random >> 48) & 0x3fff) | 0x8000; // RFC 4122 variant
+
+    // The multicast bit marks the node ID as random, not a MAC address
+    let node = (random & 0xffff_ffff_ffff) | 0x0100_0000_0000;
+    format_uuid(high, (clock_seq << 48) | node)
+}
+
+/// Write out 128 bits as a hyphenated UUID
+fn format_uuid(high: u64, low: u64) -> String {
+    format!(
+        "{:08x}-{:04x}-{:04x}-{:04x}-{:012x}",
+        high >> 32,
+        (high >> 16) & 0xffff,
+        high & 0xffff,
+        low >> 48,
+        low & 0xffff_ffff_ffff
+    )
+}
@@ -0,0 +1,328 @@
+//! # Parses the output of mkfs.btrfs
+//!
+//! `mkfs.btrfs` prints a summary of the filesystem it created to stdout.
+//! [`FormatReport`] picks the interesting bits out of that summary.
+//!
+//! # Examples
+//! ```
+//! use mkfs_btrfs_rs::report::FormatReport;
+//! let report = FormatReport::parse(
+//!     "Rootdir from:       ./testdir\n  Shrink:           yes\nFilesystem size:    114.00MiB\n",
+//! );
+//! assert_eq!(Some(119_537_664), report.shrunk_size_bytes);
+//! ```
+
+use crate::format::{ChecksumAlgorithm, DataProfile};
+use std::path::PathBuf;
+
+/// The page size of most systems (x86, and most ARM configurations)
+pub const COMMON_PAGE_SIZE: usize = 4096;
+
+/// Information reported by mkfs.btrfs after formatting.
+#[derive(Clone, Debug, Default, PartialEq, Eq)]
+pub struct FormatReport {
+    /// Size of the filesystem after `--shrink`, if it was shrunk.
+    pub shrunk_size_bytes: Option<u64>,
+    /// The size of the filesystem, from the `Filesystem size:` line, whether or not it was shrunk.
+    pub filesystem_size_bytes: Option<u64>,
+    /// The node size mkfs.btrfs used, whether it was requested or picked automatically.
+    pub node_size: Option<usize>,
+    /// The sector size mkfs.btrfs used, whether it was requested or picked automatically.
+    pub sector_size: Option<usize>,
+    /// How many devices mkfs.btrfs said it used, from the `Number of devices:` line.
+    ///
+    /// See [`device_count`](Self::device_count) for the rows of the `Devices:` table.
+    pub number_of_devices: Option<usize>,
+    /// The data block group, from `Block group profiles:`
+    pub data: Option<BlockGroup>,
+    /// The metadata block group, from `Block group profiles:`
+    pub metadata: Option<BlockGroup>,
+    /// The system block group, from `Block group profiles:`
+    pub system: Option<BlockGroup>,
+    /// The devices the filesystem was created on, from the `Devices:` table.
+    pub devices: Vec<DeviceInfo>,
+    /// Whether mkfs.btrfs listed `free-space-tree` among the features it enabled.
+    pub free_space_tree: bool,
+    /// The size of the device, measured just before formatting it.
+    ///
+    /// Only set by [`Formatter::format_reported`](crate::format::Formatter::format_reported),
+    /// with [`fill_device`](crate::format::FormatterOptions::fill_device).
+    pub device_size_bytes: Option<u64>,
+}
+
+/// A line of the `Block group profiles:` section of mkfs.btrfs' summary, like `Data:  single  8.00MiB`.
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub struct BlockGroup {
+    pub profile: DataProfile,
+    /// The size of the block groups mkfs.btrfs allocated up front
+    pub size_bytes: u64,
+}
+
+/// A device listed in the `Devices:` table of mkfs.btrfs' summary.
+#[derive(Clone, Debug, PartialEq, Eq)]
+pub struct DeviceInfo {
+    /// The btrfs device ID, starting from 1
+    pub id: u64,
+    /// The size of the device, as used by the filesystem
+    pub size_bytes: u64,
+    pub path: PathBuf,
+}
+
+impl FormatReport {
+    /// Parse the stdout of mkfs.btrfs into a FormatReport.
+    ///
+    /// Lines that aren't understood are ignored.
+    pub fn parse(stdout: &str) -> Self {
+        let mut report = FormatReport::default();
+        let mut shrink = false;
+        let mut lines = stdout.lines().peekable();
+        while let Some(line) = lines.next() {
+            if line.trim() == "Devices:" {
+                // Skip the `ID SIZE PATH` header, then read rows until the table ends
+                lines.next_if(|header| header.trim_start().starts_with("ID"));
+                while let Some(device) = lines.peek().and_then(|row| parse_device(row)) {
+                    report.devices.push(device);
+                    lines.next();
+                }
+                continue;
+            }
+            let Some((key, value)) = line.split_once(':') else {
+                continue;
+            };
+            match (key.trim(), value.trim()) {
+                ("Shrink", value) => shrink = value == "yes",
+                ("Filesystem size", value) => report.filesystem_size_bytes = parse_size(value),
+                ("Node size", value) => report.node_size = first_number(value),
+                // Newer versions add `(CPU page size: 4096)` after the size
+                ("Sector size", value) => report.sector_size = first_number(value),
+                ("Number of devices", value) => report.number_of_devices = value.parse().ok(),
+                ("Data", value) => report.data = parse_block_group(value),
+                ("Metadata", value) => report.metadata = parse_block_group(value),
+                ("System", value) => report.system = parse_block_group(value),
+                // `Features:` in newer versions, `Runtime features:` and the like in older ones
+                (key, value) if key.ends_with("features") || key.ends_with("Features") => {
+                    report.free_space_tree |= value
+                        .split(',')
+                        .any(|feature| feature.trim() == "free-space-tree");
+                }
+                _ => {}
+            }
+        }
+        report.shrunk_size_bytes = report.filesystem_size_bytes.filter(|_| shrink);
+        report
+    }
+
+    /// How many devices the filesystem was created on.
+    ///
+    /// # Examples
+    /// ```
+    /// use mkfs_btrfs_rs::report::FormatReport;
+    /// let report = FormatReport::parse(
+    ///     "Devices:\n   ID        SIZE  PATH\n    1     1.00GiB  /dev/sdb\n    2     1.00GiB  /dev/sdc\n",
+    /// );
+    /// assert_eq!(2, report.device_count());
+    /// assert_eq!(2 << 30, report.total_size_bytes());
+    /// ```
+    pub fn device_count(&self) -> usize {
+        self.devices.len()
+    }
+
+    /// The combined size of every device the filesystem was created on.
+    pub fn total_size_bytes(&self) -> u64 {
+        self.devices.iter().map(|device| device.size_bytes).sum()
+    }
+
+    /// Render the report as a JSON object, for provisioning systems to record.
+    ///
+    /// Enabled by the `json` feature. Unknown values are `null`.
+    ///
+    /// # Examples
+    /// ```
+    /// use mkfs_btrfs_rs::report::FormatReport;
+    /// let report = FormatReport::parse("Sector size:        4096\n");
+    /// assert_eq!(
+    ///     concat!(
+    ///         r#"{"shrunk_size_bytes":null,"filesystem_size_bytes":null,"node_size":null,"#,
+    ///         r#""sector_size":4096,"number_of_devices":null,"data":null,"metadata":null,"#,
+    ///         r#""system":null,"free_space_tree":false,"device_size_bytes":null,"devices":[]}"#,
+    ///     ),
+    ///     report.to_json()
+    /// );
+    /// ```
+    #[cfg(feature = "json")]
+    pub fn to_json(&self) -> String {
+        let number = |n: Option<u64>| n.map_or(String::from("null"), |n| n.to_string());
+        let block_group =
+            |group: Option<BlockGroup>| group.map_or(String::from("null"), |group| group.to_json());
+        let devices: Vec<String> = self.devices.iter().map(DeviceInfo::to_json).collect();
+        format!(
+            concat!(
+                r#"{{"shrunk_size_bytes":{},"filesystem_size_bytes":{},"node_size":{},"#,
+                r#""sector_size":{},"number_of_devices":{},"data":{},"metadata":{},"#,
+                r#""system":{},"free_space_tree":{},"device_size_bytes":{},"devices":[{}]}}"#,
+            ),
+            number(self.shrunk_size_bytes),
+            number(self.filesystem_size_bytes),
+            number(self.node_size.map(|size| size as u64)),
+            number(self.sector_size.map(|size| size as u64)),
+            number(self.number_of_devices.map(|count| count as u64)),
+            block_group(self.data),
+            block_group(self.metadata),
+            block_group(self.system),
+            self.free_space_tree,
+            number(self.device_size_bytes),
+            devices.join(",")
+        )
+    }
+
+    /// Warn if the sector size isn't [`COMMON_PAGE_SIZE`].
+    ///
+    /// Linux only mounts btrfs filesystems whose sector size is supported by
+    /// the running kernel, which is usually just its page size.
+    ///
+    /// # Examples
+    /// ```
+    /// use mkfs_btrfs_rs::report::FormatReport;
+    /// let report = FormatReport::parse("Sector size:        65536\n");
+    /// assert!(report.sector_size_warning().is_some());
+    /// ```
+    pub fn sector_size_warning(&self) -> Option<String> {
+        self.sector_size
+            .filter(|&sector_size| sector_size != COMMON_PAGE_SIZE)
+            .map(|sector_size| {
+                format!(
+                    "sector size ( = {sector_size} ) differs from the common page size ( = {COMMON_PAGE_SIZE} ); \
+                     the filesystem may not mount on most systems"
+                )
+            })
+    }
+}
+
+impl BlockGroup {
+    /// Render the block group as a JSON object. Enabled by the `json` feature.
+    #[cfg(feature = "json")]
+    pub fn to_json(&self) -> String {
+        format!(
+            r#"{{"profile":"{}","size_bytes":{}}}"#,
+            self.profile, self.size_bytes
+        )
+    }
+}
+
+impl DeviceInfo {
+    /// Render the device as a JSON object. Enabled by the `json` feature.
+    #[cfg(feature = "json")]
+    pub fn to_json(&self) -> String {
+        format!(
+            r#"{{"id":{},"size_bytes":{},"path":{}}}"#,
+            self.id,
+            self.size_bytes,
+            json_string(&self.path.to_string_lossy())
+        )
+    }
+}
+
+/// Quote and escape a string for JSON
+#[cfg(feature = "json")]
+fn json_string(s: &str) -> String {
+    let mut json = String::with_capacity(s.len() + 2);
+    json.push('"');
+    for c in s.chars() {
+        match c {
+            '"' => json.push_str("\\\""),
+            '\\' => json.push_str("\\\\"),
+            '\n' => json.push_str("\\n"),
+            c if c.is_control() => json.push_str(&format!("\\u{:04x}", c as u32)),
+            c => json.push(c),
+        }
+    }
+    json.push('"');
+    json
+}
+
+/// Parse the first whitespace-separated word of a value as a number
+fn first_number(value: &str) -> Option<usize> {
+    value.split_whitespace().next()?.parse().ok()
+}
+
+/// Parse a line of the `Block group profiles:` section, after the key, like `DUP   51.19MiB`.
+fn parse_block_group(value: &str) -> Option<BlockGroup> {
+    let mut columns = value.split_whitespace();
+    let profile = columns.next()?.parse().ok()?;
+    let size_bytes = parse_size(columns.next()?)?;
+    Some(BlockGroup {
+        profile,
+        size_bytes,
+    })
+}
+
+/// Parse a row of the `Devices:` table, like `    1   512.00MiB  /dev/sdb`.
+///
+/// Any columns between the size and the path (like zoned devices' `ZONES`) are skipped.
+fn parse_device(row: &str) -> Option<DeviceInfo> {
+    let mut columns = row.split_whitespace();
+    let id = columns.next()?.parse().ok()?;
+    let size_bytes = parse_size(columns.next()?)?;
+    let path = PathBuf::from(columns.last()?);
+    Some(DeviceInfo {
+        id,
+        size_bytes,
+        path,
+    })
+}
+
+/// Parse the output of `mkfs.btrfs -O list-all` into the names of the features it knows.
+///
+/// Each feature is listed as `name - description`; aliases are kept, since
+/// mkfs.btrfs accepts them too.
+pub(crate) fn parse_feature_list(output: &str) -> Vec<String> {
+    output
+        .lines()
+        .filter_map(|line| line.split_once(" - "))
+        .map(|(name, _)| name.trim())
+        .filter(|name| !name.is_empty() && !name.contains(char::is_whitespace))
+        .map(String::from)
+        .collect()
+}
+
+/// Find the checksum algorithm in the output of `btrfs inspect-internal dump-super`.
+///
+/// Reads the `csum_type` line, like `csum_type 1 (xxhash64)`, by its on-disk number.
+pub(crate) fn parse_csum_type(dump: &str) -> Option<ChecksumAlgorithm> {
+    let line = dump
+        .lines()
+        .find(|line| line.split_whitespace().next() == Some("csum_type"))?;
+    match line.split_whitespace().nth(1)? {
+        "0" => Some(ChecksumAlgorithm::Crc32c),
+        "1" => Some(ChecksumAlgorithm::XxHash),
+        "2" => Some(ChecksumAlgorithm::Sha256),
+        "3" => Some(ChecksumAlgorithm::Blake2),
+        _ => None,
+    }
+}
+
+/// Parse a size as printed by mkfs.btrfs (e.g. `114.00MiB`, or `119.54MB` in SI units) into bytes.
+pub(crate) fn parse_size(size: &str) -> Option<u64> {
+    let split = size
+        .find(|c: char| !(c.is_ascii_digit() || c == '.'))
+        .unwrap_or(size.len());
+    let (number, unit) = size.split_at(split);
+    let multiplier: u64 = match unit.trim() {
+        "" | "B" => 1,
+        "KiB" => 1 << 10,
+        "MiB" => 1 << 20,
+        "GiB" => 1 << 30,
+        "TiB" => 1 << 40,
+        "PiB" => 1 << 50,
+        "EiB" => 1 << 60,
+        "kB" | "KB" => 1_000,
+        "MB" => 1_000_000,
+        "GB" => 1_000_000_000,
+        "TB" => 1_000_000_000_000,
+        "PB" => 1_000_000_000_000_000,
+        "EB" => 1_000_000_000_000_000_000,
+        _ => return None,
+    };
+    let number: f64 = number.parse().ok()?;
+    Some((number * multiplier as f64).round() as u64)
+}
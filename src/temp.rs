@@ -0,0 +1,105 @@
+//! # Temporary files, removed on drop
+//!
+//! Enabled by the `tempfile` feature.
+//!
+//! # Examples
+//! ```
+//! # use mkfs_btrfs_rs::Error;
+//! use mkfs_btrfs_rs::temp::TempFile;
+//! let image = TempFile::new(4096)?;
+//! assert_eq!(4096, image.path().metadata()?.len());
+//! # Ok::<(), Error>(())
+//! ```
+
+use std::{
+    fs::{self, OpenOptions},
+    io::{ErrorKind, Result as IoResult},
+    path::{Path, PathBuf},
+    sync::atomic::{AtomicU64, Ordering},
+};
+
+/// Distinguishes temp paths made by this process
+static COUNTER: AtomicU64 = AtomicU64::new(0);
+
+/// Find an unused path in the system temp dir, and create `make` there
+fn unique<T>(kind: &str, make: impl Fn(&Path) -> IoResult<T>) -> IoResult<(PathBuf, T)> {
+    loop {
+        let path = std::env::temp_dir().join(format!(
+            "mkfs-btrfs-rs-{}-{}.{kind}",
+            std::process::id(),
+            COUNTER.fetch_add(1, Ordering::Relaxed)
+        ));
+        match make(&path) {
+            Ok(made) => return Ok((path, made)),
+            // Left over from an earlier process with the same pid
+            Err(e) if e.kind() == ErrorKind::AlreadyExists => continue,
+            Err(e) => return Err(e),
+        }
+    }
+}
+
+/// A file in the system temp dir, deleted when dropped.
+#[derive(Debug)]
+pub struct TempFile {
+    path: PathBuf,
+}
+
+impl TempFile {
+    /// Create a (sparse) temp file, `size` bytes long
+    pub fn new(size: u64) -> IoResult<Self> {
+        let (path, file) = unique("img", |path| {
+            OpenOptions::new().write(true).create_new(true).open(path)
+        })?;
+        let temp = TempFile { path };
+        file.set_len(size)?;
+        Ok(temp)
+    }
+    /// Where the file lives
+    pub fn path(&self) -> &Path {
+        &self.path
+    }
+}
+
+impl AsRef<Path> for TempFile {
+    fn as_ref(&self) -> &Path {
+        self.path()
+    }
+}
+
+impl Drop for TempFile {
+    fn drop(&mut self) {
+        // Nothing sensible to do if it's already gone
+        let _ = fs::remove_file(&self.path);
+    }
+}
+
+/// A directory in the system temp dir, deleted along with its contents when dropped.
+#[derive(Debug, Hash, PartialEq, Eq, PartialOrd, Ord)]
+pub struct TempDir {
+    path: PathBuf,
+}
+
+impl TempDir {
+    /// Create an empty temp directory
+    pub fn new() -> IoResult<Self> {
+        let (path, ()) = unique("d", |path| fs::create_dir(path))?;
+        Ok(TempDir { path })
+    }
+    /// Where the directory lives
+    pub fn path(&self) -> &Path {
+        &self.path
+    }
+}
+
+impl AsRef<Path> for TempDir {
+    fn as_ref(&self) -> &Path {
+        self.path()
+    }
+}
+
+impl Drop for TempDir {
+    fn drop(&mut self) {
+        // Nothing sensible to do if it's already gone
+        let _ = fs::remove_dir_all(&self.path);
+    }
+}
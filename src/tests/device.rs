@@ -0,0 +1,63 @@
+//! Tests for inspecting devices
+
+use super::scratch_file;
+use crate::device::*;
+
+#[cfg(target_os = "linux")]
+#[test]
+fn regular_file_doesnt_back_root() {
+    let file = scratch_file("regular_file_doesnt_back_root");
+    assert!(!backs_root(&file).unwrap());
+    assert!(!backs_root(std::path::Path::new("/")).unwrap());
+}
+
+#[cfg(all(
+    target_os = "linux",
+    any(target_arch = "x86_64", target_arch = "aarch64")
+))]
+#[test]
+fn blkgetsize64() {
+    assert_eq!(0x8008_1272, BLKGETSIZE64);
+}
+
+#[test]
+fn probe_filesystem_finds_btrfs() {
+    let file = scratch_file("probe_filesystem");
+    assert_eq!(None, probe_filesystem(&file).unwrap());
+    let mut image = vec![0; 0x1_1000];
+    image[0x1_0040..0x1_0048].copy_from_slice(b"_BHRfS_M");
+    std::fs::write(&file, image).unwrap();
+    assert_eq!(Some("btrfs"), probe_filesystem(&file).unwrap());
+}
+
+#[cfg(target_os = "linux")]
+#[test]
+fn rotational_from_sysfs() {
+    use std::fs;
+    let sysfs = super::scratch_dir().join("sysfs");
+    let _ = fs::remove_dir_all(&sysfs);
+    let disk = sysfs.join("devices/sda");
+    fs::create_dir_all(disk.join("queue")).unwrap();
+    fs::create_dir_all(disk.join("sda1")).unwrap();
+    fs::write(disk.join("queue/rotational"), "1\n").unwrap();
+    let ssd = sysfs.join("devices/nvme0n1");
+    fs::create_dir_all(ssd.join("queue")).unwrap();
+    fs::write(ssd.join("queue/rotational"), "0\n").unwrap();
+    let block = sysfs.join("dev/block");
+    fs::create_dir_all(&block).unwrap();
+    std::os::unix::fs::symlink(&disk, block.join("8:0")).unwrap();
+    std::os::unix::fs::symlink(disk.join("sda1"), block.join("8:1")).unwrap();
+    std::os::unix::fs::symlink(&ssd, block.join("259:0")).unwrap();
+
+    assert_eq!(Some(true), rotational_in(&sysfs, join_dev(8, 0)));
+    assert_eq!(Some(true), rotational_in(&sysfs, join_dev(8, 1)));
+    assert_eq!(Some(false), rotational_in(&sysfs, join_dev(259, 0)));
+    assert_eq!(None, rotational_in(&sysfs, join_dev(7, 0)));
+}
+
+#[cfg(target_os = "linux")]
+#[test]
+fn files_arent_rotational() {
+    let file = scratch_file("files_arent_rotational");
+    assert_eq!(None, is_rotational(&file).unwrap());
+}
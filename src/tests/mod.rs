@@ -4,7 +4,52 @@
 
 use crate::{format::*, Result};
 
-use std::process::Command;
+use std::{fs, path::PathBuf, process::Command};
+#[cfg(unix)]
+use std::{fs::Permissions, os::unix::fs::PermissionsExt};
+
+mod device;
+mod options;
+mod report;
+// The stubs standing in for mkfs.btrfs are shell scripts
+#[cfg(unix)]
+mod run;
+
+/// Directory the tests can scribble in
+fn scratch_dir() -> PathBuf {
+    let dir = std::env::temp_dir().join("mkfs-btrfs-rs-tests");
+    fs::create_dir_all(&dir).expect("temp dir should be writable");
+    dir
+}
+
+/// Write an executable shell script to stand in for mkfs.btrfs
+#[cfg(unix)]
+fn stub(name: &str, script: &str) -> PathBuf {
+    let path = scratch_dir().join(format!("{name}.sh"));
+    fs::write(&path, format!("#!/bin/sh\n{script}\n")).expect("stub should be writable");
+    fs::set_permissions(&path, Permissions::from_mode(0o755)).expect("stub should be executable");
+    path
+}
+
+/// Create an empty file to format
+fn scratch_file(name: &str) -> PathBuf {
+    let path = scratch_dir().join(name);
+    fs::write(&path, b"").expect("scratch file should be writable");
+    path
+}
+
+#[cfg(not(target_os = "linux"))]
+#[test]
+fn unsupported_platform() {
+    let err = Formatter::options()
+        .build()
+        .format(scratch_file("unsupported_platform"))
+        .expect_err("mkfs.btrfs only runs on Linux");
+    assert!(
+        matches!(err, crate::Error::UnsupportedPlatform(_)),
+        "{err:?}"
+    );
+}
 
 mod checksum {
     use crate::format::ChecksumAlgorithm;
@@ -15,6 +60,138 @@ mod checksum {
         assert_eq!("sha256", format!("{}", ChecksumAlgorithm::Sha256));
         assert_eq!("xxhash", format!("{}", ChecksumAlgorithm::XxHash));
     }
+    #[test]
+    fn string() {
+        assert_eq!("sha256", String::from(ChecksumAlgorithm::Sha256));
+        assert_eq!("xxhash", ChecksumAlgorithm::XxHash.as_ref());
+    }
+    #[test]
+    fn from_str() {
+        assert!(matches!("blake2".parse(), Ok(ChecksumAlgorithm::Blake2)));
+        assert!("md5".parse::<ChecksumAlgorithm>().is_err());
+    }
+    #[test]
+    fn all() {
+        let names: Vec<String> = ChecksumAlgorithm::ALL
+            .iter()
+            .map(|algorithm| algorithm.to_string())
+            .collect();
+        // Display names are unique, so this also checks each variant appears once
+        assert_eq!(["crc32c", "xxhash", "sha256", "blake2"], names.as_slice());
+    }
+    #[test]
+    fn default() {
+        assert!(matches!(
+            ChecksumAlgorithm::default(),
+            ChecksumAlgorithm::Crc32c
+        ));
+    }
+}
+
+mod data_profile {
+    use crate::format::DataProfile;
+    #[test]
+    fn display() {
+        assert_eq!("raid1c3", format!("{}", DataProfile::Raid1c3));
+        assert_eq!("dup", format!("{}", DataProfile::Dup));
+    }
+    #[test]
+    fn string() {
+        assert_eq!("raid10", String::from(DataProfile::Raid10));
+        assert_eq!("single", DataProfile::Single.as_ref());
+    }
+    #[test]
+    fn from_str() {
+        assert!(matches!("raid1c4".parse(), Ok(DataProfile::Raid1c4)));
+        assert!(matches!("DUP".parse(), Ok(DataProfile::Dup)));
+        assert!("raid7".parse::<DataProfile>().is_err());
+    }
+    #[test]
+    fn all() {
+        let names: Vec<String> = DataProfile::ALL.iter().map(|p| p.to_string()).collect();
+        assert_eq!(
+            ["raid0", "raid1", "raid1c3", "raid1c4", "raid5", "raid6", "raid10", "single", "dup"],
+            names.as_slice()
+        );
+        for profile in DataProfile::ALL {
+            assert_eq!(
+                1,
+                DataProfile::ALL.iter().filter(|&&p| p == profile).count()
+            );
+        }
+    }
+    #[test]
+    fn min_devices() {
+        use DataProfile::*;
+        for (profile, devices) in [
+            (Single, 1),
+            (Dup, 1),
+            (Raid0, 2),
+            (Raid1, 2),
+            (Raid5, 2),
+            (Raid1c3, 3),
+            (Raid6, 3),
+            (Raid1c4, 4),
+            (Raid10, 4),
+        ] {
+            assert_eq!(devices, profile.min_devices(), "{profile}");
+        }
+    }
+}
+
+mod features {
+    use crate::format::{Formatter, MkfsFeature};
+    use std::ffi::OsString;
+    #[test]
+    fn display() {
+        assert_eq!("mixed-bg", format!("{}", MkfsFeature::MixedBg));
+        assert_eq!(
+            "block-group-tree",
+            format!("{}", MkfsFeature::BlockGroupTree)
+        );
+        assert_eq!("^extref", format!("{}", MkfsFeature::Extref.disable()));
+    }
+    #[test]
+    fn typed_args() {
+        let options = Formatter::options()
+            .features([
+                MkfsFeature::SkinnyMetadata,
+                MkfsFeature::NoHoles.disable(),
+                MkfsFeature::Unset(Box::new(MkfsFeature::Raid56)),
+            ])
+            .unwrap();
+        assert_eq!(
+            vec![OsString::from(
+                "--features=skinny-metadata,^no-holes,^raid56"
+            )],
+            options.to_args()
+        );
+    }
+    #[test]
+    fn reject_separators() {
+        Formatter::options()
+            .features(["mixed-bg"])
+            .expect("mixed-bg is a single feature");
+        Formatter::options()
+            .features(["a,b"])
+            .expect_err("Commas would split the feature");
+        Formatter::options()
+            .features(["a b"])
+            .expect_err("Spaces would split the feature");
+        Formatter::options()
+            .runtime_features(["quota,free-space-tree"])
+            .expect_err("Commas would split the runtime feature");
+    }
+    #[test]
+    fn string_args() {
+        let options = Formatter::options()
+            .features(["mixed-bg", "^no-holes"])
+            .unwrap();
+        assert_eq!(
+            vec![OsString::from("--features=mixed-bg,^no-holes")],
+            options.to_args()
+        );
+    }
 }
 
 /// Test every single option
@@ -67,8 +244,8 @@ fn format_start_to_finish() -> Result<()> {
     assert!(
         output.status.success(),
         "> STDOUT:\n{}\n> STDERR:\n{}",
-        String::from_utf8(output.stdout).unwrap(),
-        String::from_utf8(output.stderr).unwrap(),
+        output.stdout_str(),
+        output.stderr_str(),
     );
     Command::new("rm").arg(path).output()?;
     Ok(())
@@ -82,3 +259,68 @@ fn very_long_label() {
         .label(&label)
         .expect_err("Must reject labels greater than 255 bytes");
 }
+
+/// The label limit is in bytes, not characters
+#[test]
+fn multibyte_label() {
+    // 3 bytes each; 85 of them are exactly 255 bytes
+    let accepted = "\u{2603}".repeat(85);
+    assert_eq!(255, accepted.len());
+    Formatter::options()
+        .label(&accepted)
+        .expect("255 bytes is the limit");
+    let rejected = format!("{accepted}A");
+    assert_eq!(86, rejected.chars().count());
+    match Formatter::options().label(&rejected) {
+        Err(crate::Error::ArgumentError { option, message }) => {
+            assert_eq!("label", option);
+            assert!(message.contains("255 bytes (UTF-8), not 256"), "{message}");
+        }
+        other => panic!("Expected a 256 byte label to be rejected, got {other:?}"),
+    }
+    // 64 emoji are only 64 characters, but 256 bytes
+    Formatter::options()
+        .label("\u{1F600}".repeat(64))
+        .expect_err("4 byte characters add up quickly");
+}
+
+#[test]
+fn random_label() {
+    let label = |options: FormatterOptions| options.to_args()[0].clone();
+    let first = label(Formatter::options().random_label("farm-").unwrap());
+    let second = label(Formatter::options().random_label("farm-").unwrap());
+    assert_ne!(first, second);
+    let long = label(Formatter::options().random_label(&"A".repeat(247)).unwrap());
+    assert_eq!("--label=".len() + 255, long.len());
+    Formatter::options()
+        .random_label(&"A".repeat(248))
+        .expect_err("No room for the suffix");
+}
+
+#[test]
+fn label_str_or_string() {
+    let borrowed = Formatter::options().label("owned-or-not").unwrap();
+    let owned = Formatter::options()
+        .label(String::from("owned-or-not"))
+        .unwrap();
+    assert_eq!(borrowed, owned);
+    Formatter::options()
+        .label("A".repeat(256))
+        .expect_err("Owned labels are checked too");
+}
+
+#[test]
+fn empty_label() {
+    assert!(matches!(
+        Formatter::options().label(""),
+        Err(crate::Error::ArgumentError {
+            option: "label",
+            ..
+        })
+    ));
+    let options = Formatter::options()
+        .allow_empty_label()
+        .and_then(|options| options.label(""))
+        .unwrap();
+    assert!(options.build().iter_args().any(|arg| arg == "--label="));
+}
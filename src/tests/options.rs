@@ -0,0 +1,1114 @@
+//! Tests for FormatterOptions
+
+use super::scratch_dir;
+#[cfg(unix)]
+use super::stub;
+use crate::format::*;
+use std::{ffi::OsString, fs};
+
+#[test]
+fn equality() {
+    let build = || {
+        Formatter::options()
+            .label("equal")
+            .and_then(|options| options.data(DataProfile::Single))
+            .unwrap()
+    };
+    assert_eq!(build(), build());
+    assert_ne!(build(), build().label("different").unwrap());
+    assert_ne!(build(), Formatter::options());
+}
+
+#[test]
+fn uuid() {
+    Formatter::options()
+        .uuid("73e1b7e2-a3a8-49c2-b258-06f01a889bba")
+        .expect("This uuid is of the correct format");
+    Formatter::options()
+        .uuid("00000000-0000-0000-0000-000000000000")
+        .expect_err("Must reject the nil UUID");
+    Formatter::options()
+        .uuid("73e1b7e2a3a849c2b25806f01a889bba")
+        .expect_err("Must reject UUIDs without hyphens");
+    Formatter::options()
+        .uuid("73e1b7e2-a3a8-49c2-b258-06f01a889bbz")
+        .expect_err("Must reject non-hex UUIDs");
+    let err = Formatter::options()
+        .device_uuid("73e1b7e2a3a849c2b25806f01a889bba")
+        .expect_err("Device UUIDs are checked the same way");
+    assert!(
+        matches!(
+            err,
+            crate::Error::ArgumentError {
+                option: "device_uuid",
+                ..
+            }
+        ),
+        "{err:?}"
+    );
+}
+
+#[test]
+fn profile() {
+    let options = Formatter::options().profile(DataProfile::Single).unwrap();
+    assert_eq!(
+        vec![
+            OsString::from("--data=single"),
+            OsString::from("--metadata=single")
+        ],
+        options.to_args()
+    );
+    assert_eq!(
+        options,
+        Formatter::options()
+            .data(DataProfile::Single)
+            .and_then(|options| options.metadata(DataProfile::Single))
+            .unwrap()
+    );
+}
+
+#[test]
+fn try_build() {
+    Formatter::options()
+        .mixed()
+        .and_then(|options| options.data(DataProfile::Single))
+        .and_then(|options| options.metadata(DataProfile::Dup))
+        .unwrap()
+        .try_build()
+        .expect_err("Mixed mode requires data and metadata to match");
+    Formatter::options()
+        .features(["mixed-bg"])
+        .and_then(|options| options.data(DataProfile::Single))
+        .and_then(|options| options.metadata(DataProfile::Dup))
+        .unwrap()
+        .try_build()
+        .expect_err("The mixed-bg feature implies mixed mode");
+    Formatter::options()
+        .data(DataProfile::Single)
+        .and_then(|options| options.metadata(DataProfile::Dup))
+        .unwrap()
+        .try_build()
+        .expect("Profiles may differ when not mixed");
+    let options = Formatter::options()
+        .mixed()
+        .and_then(|options| options.profile(DataProfile::Dup))
+        .unwrap();
+    assert_eq!(options.build(), options.try_build().unwrap());
+}
+
+#[test]
+fn metadata_redundant() {
+    let options = Formatter::options().metadata_redundant().unwrap();
+    assert_eq!(vec![OsString::from("--metadata=dup")], options.to_args());
+}
+
+#[test]
+fn mixed_block_sizes() {
+    let mixed = |nodesize, sectorsize| {
+        Formatter::options()
+            .mixed()
+            .and_then(|options| options.nodesize(nodesize))
+            .and_then(|options| options.sectorsize(sectorsize))
+            .unwrap()
+    };
+    mixed(4096, 4096)
+        .try_build()
+        .expect("Equal block sizes are fine in mixed mode");
+    mixed(16384, 4096)
+        .try_build()
+        .expect_err("Mixed mode requires nodesize == sectorsize");
+    Formatter::options()
+        .nodesize(16384)
+        .and_then(|options| options.sectorsize(4096))
+        .unwrap()
+        .try_build()
+        .expect("Block sizes may differ when not mixed");
+}
+
+/// The only test that touches MKFS_BTRFS_* variables, so it can't race with itself
+#[test]
+fn from_env() {
+    std::env::set_var("MKFS_BTRFS_LABEL", "from-env");
+    std::env::set_var("MKFS_BTRFS_DATA", "raid1");
+    std::env::set_var("MKFS_BTRFS_CHECKSUM", "xxhash");
+    std::env::set_var("MKFS_BTRFS_FORCE", "yes");
+    let options = FormatterOptions::from_env().expect("All variables are valid");
+    assert_eq!(
+        vec![
+            OsString::from("--checksum=xxhash"),
+            OsString::from("--data=raid1"),
+            OsString::from("--force"),
+            OsString::from("--label=from-env"),
+        ],
+        options.to_args()
+    );
+    // Empty variables are skipped, rather than asking for an empty feature or a number
+    std::env::set_var("MKFS_BTRFS_FEATURES", "");
+    std::env::set_var("MKFS_BTRFS_NODESIZE", " ");
+    assert_eq!(
+        options.to_args(),
+        FormatterOptions::from_env()
+            .expect("Empty variables are unset")
+            .to_args()
+    );
+    std::env::remove_var("MKFS_BTRFS_FEATURES");
+
+    std::env::set_var("MKFS_BTRFS_DATA", "raid7");
+    FormatterOptions::from_env().expect_err("raid7 is not a DataProfile");
+    std::env::set_var("MKFS_BTRFS_DATA", "raid1");
+    std::env::set_var("MKFS_BTRFS_NODESIZE", "lots");
+    FormatterOptions::from_env().expect_err("nodesize must be a number");
+    std::env::remove_var("MKFS_BTRFS_NODESIZE");
+
+    // The preset goes first, so MKFS_BTRFS_CHECKSUM overrides its crc32c
+    std::env::set_var("MKFS_BTRFS_PRESET", "ssd-optimized");
+    let options = FormatterOptions::from_env().expect("All variables are valid");
+    assert_eq!(
+        FormatterOptions::default()
+            .preset(Preset::SsdOptimized)
+            .and_then(|options| options.checksum(ChecksumAlgorithm::XxHash))
+            .and_then(|options| options.data(DataProfile::Raid1))
+            .and_then(|options| options.force())
+            .and_then(|options| options.label("from-env"))
+            .unwrap()
+            .to_args(),
+        options.to_args()
+    );
+    std::env::set_var("MKFS_BTRFS_PRESET", "fastest");
+    FormatterOptions::from_env().expect_err("fastest is not a Preset");
+
+    for var in [
+        "MKFS_BTRFS_LABEL",
+        "MKFS_BTRFS_DATA",
+        "MKFS_BTRFS_CHECKSUM",
+        "MKFS_BTRFS_FORCE",
+        "MKFS_BTRFS_FEATURES",
+        "MKFS_BTRFS_NODESIZE",
+        "MKFS_BTRFS_PRESET",
+    ] {
+        std::env::remove_var(var);
+    }
+}
+
+#[test]
+fn deterministic() {
+    let options = |features: [&'static str; 3]| {
+        Formatter::options()
+            .features(features)
+            .and_then(|options| options.runtime_features(["quota", "free-space-tree"]))
+            .and_then(|options| options.deterministic())
+            .unwrap()
+    };
+    let forwards = options(["mixed-bg", "no-holes", "^extref"]);
+    let backwards = options(["^extref", "no-holes", "mixed-bg"]);
+    assert_eq!(forwards.to_args(), backwards.to_args());
+    assert_eq!(
+        vec![
+            OsString::from("--features=^extref,mixed-bg,no-holes"),
+            OsString::from("--runtime-features=free-space-tree,quota"),
+        ],
+        forwards.to_args()
+    );
+}
+
+#[test]
+fn is_set() {
+    let options = Formatter::options()
+        .label("set")
+        .and_then(|options| options.force())
+        .unwrap();
+    assert!(options.is_set(Field::Label));
+    assert!(options.is_set(Field::Force));
+    assert!(!options.is_set(Field::Data));
+    assert!(!options.is_set(Field::Uuid));
+    assert!(!Formatter::options().is_set(Field::Label));
+}
+
+#[test]
+fn rootdir_sized() {
+    let dir = scratch_dir().join("rootdir_sized");
+    fs::create_dir_all(dir.join("nested")).unwrap();
+    // Sparse files, so the test doesn't actually write 160MiB
+    fs::File::create(dir.join("a"))
+        .and_then(|file| file.set_len(100 * 1024 * 1024))
+        .unwrap();
+    fs::File::create(dir.join("nested/b"))
+        .and_then(|file| file.set_len(60 * 1024 * 1024))
+        .unwrap();
+    let content = 160 * 1024 * 1024;
+
+    let options = Formatter::options().rootdir_sized(&dir).unwrap();
+    assert_eq!(
+        OsString::from(format!("--byte-count={}", content + content / 2)),
+        options.to_args()[0]
+    );
+    let options = Formatter::options()
+        .rootdir_sized_with_overhead(&dir, 0)
+        .unwrap();
+    assert_eq!(
+        OsString::from(format!("--byte-count={content}")),
+        options.to_args()[0]
+    );
+    Formatter::options()
+        .rootdir_sized_with_overhead(&dir, u64::MAX)
+        .expect_err("The overhead overflows");
+
+    let empty = scratch_dir().join("rootdir_sized_empty");
+    fs::create_dir_all(&empty).unwrap();
+    let options = Formatter::options().rootdir_sized(&empty).unwrap();
+    assert_eq!(
+        OsString::from(format!("--byte-count={MIN_ROOTDIR_BYTE_COUNT}")),
+        options.to_args()[0]
+    );
+}
+
+#[test]
+fn rootdir_with_slack() {
+    let dir = scratch_dir().join("rootdir_with_slack");
+    fs::create_dir_all(&dir).unwrap();
+    fs::File::create(dir.join("a"))
+        .and_then(|file| file.set_len(200 * 1024 * 1024))
+        .unwrap();
+    let content: u64 = 200 * 1024 * 1024;
+
+    let options = Formatter::options().rootdir_with_slack(&dir, 0.0).unwrap();
+    assert_eq!(
+        OsString::from(format!("--byte-count={content}")),
+        options.to_args()[0]
+    );
+    let options = Formatter::options().rootdir_with_slack(&dir, 0.5).unwrap();
+    assert_eq!(
+        OsString::from(format!("--byte-count={}", content + content / 2)),
+        options.to_args()[0]
+    );
+    Formatter::options()
+        .rootdir_with_slack(&dir, 1e12)
+        .expect_err("The slack overflows");
+    Formatter::options()
+        .rootdir_with_slack(&dir, -0.5)
+        .expect_err("The slack is negative");
+    Formatter::options()
+        .rootdir_with_slack(&dir, f64::NAN)
+        .expect_err("The slack is NaN");
+}
+
+#[test]
+#[cfg(unix)]
+fn rootdir_stats() {
+    use std::path::PathBuf;
+    let dir = scratch_dir().join("rootdir_stats");
+    let _ = fs::remove_dir_all(&dir);
+    fs::create_dir_all(dir.join("nested")).unwrap();
+    fs::write(dir.join("a"), "12345").unwrap();
+    fs::hard_link(dir.join("a"), dir.join("nested/a-link")).unwrap();
+    fs::write(dir.join("nested/b"), "123").unwrap();
+    std::os::unix::fs::symlink("a", dir.join("c")).unwrap();
+
+    let stats = Formatter::rootdir_stats(&dir).unwrap();
+    assert_eq!(4, stats.file_count);
+    // The hardlinked file is counted once; the symlink is as long as its target path
+    assert_eq!(5 + 3 + 1, stats.total_bytes);
+    assert_eq!(
+        vec![vec![PathBuf::from("a"), PathBuf::from("nested/a-link")]],
+        stats.hardlinks
+    );
+    Formatter::rootdir_stats(dir.join("a")).expect_err("Not a directory");
+}
+
+#[test]
+fn argument_error_option() {
+    let err = Formatter::options()
+        .nodesize(3000)
+        .expect_err("3000 is not a power of 2");
+    match &err {
+        crate::Error::ArgumentError { option, message } => {
+            assert_eq!("nodesize", *option);
+            assert_eq!(*message, err.to_string());
+        }
+        err => panic!("Expected an ArgumentError, got {err:?}"),
+    }
+}
+
+#[test]
+fn to_shell_script() {
+    let script = Formatter::options()
+        .byte_count(1 << 30)
+        .and_then(|options| options.label("it's mine"))
+        .and_then(|options| options.features(["mixed-bg", "^no-holes"]))
+        .unwrap()
+        .to_shell_script("/tmp/my image.btrfs");
+    assert_eq!(
+        "truncate --size=1073741824 '/tmp/my image.btrfs' && mkfs.btrfs --byte-count=1073741824 \
+         --features=mixed-bg,^no-holes '--label=it'\\''s mine' '/tmp/my image.btrfs'",
+        script
+    );
+}
+
+#[test]
+fn layout_constructors() {
+    for (options, profile) in [
+        (Formatter::single(), "single"),
+        (Formatter::raid1(), "raid1"),
+        (Formatter::raid10(), "raid10"),
+    ] {
+        assert_eq!(
+            vec![
+                OsString::from(format!("--data={profile}")),
+                OsString::from(format!("--metadata={profile}")),
+            ],
+            options.unwrap().to_args()
+        );
+    }
+}
+
+#[test]
+fn byte_count_mib() {
+    let options = Formatter::options().byte_count_mib(512).unwrap();
+    assert_eq!(
+        vec![OsString::from("--byte-count=536870912")],
+        options.to_args()
+    );
+    Formatter::options()
+        .byte_count_mib(u64::MAX / (1024 * 1024))
+        .expect("The largest MiB count that fits");
+    let err = Formatter::options()
+        .byte_count_mib(u64::MAX / (1024 * 1024) + 1)
+        .expect_err("Overflows a u64");
+    assert!(matches!(
+        err,
+        crate::Error::ArgumentError {
+            option: "byte_count",
+            ..
+        }
+    ));
+}
+
+#[test]
+fn rootdir_canonicalized() {
+    let options = Formatter::options().rootdir("src").unwrap();
+    let expected = std::env::current_dir().unwrap().join("src");
+    assert_eq!(
+        vec![OsString::from(format!("--rootdir={}", expected.display()))],
+        options.to_args()
+    );
+    let err = Formatter::options()
+        .rootdir("does/not/exist")
+        .expect_err("The rootdir must exist");
+    assert!(matches!(
+        err,
+        crate::Error::ArgumentError {
+            option: "rootdir",
+            ..
+        }
+    ));
+}
+
+#[test]
+fn profile_getters() {
+    let options = Formatter::options().data(DataProfile::Raid1).unwrap();
+    assert_eq!(Some(DataProfile::Raid1), options.data_profile());
+    assert_eq!(None, options.metadata_profile());
+    let options = options.metadata(DataProfile::Raid1c3).unwrap();
+    assert_eq!(Some(DataProfile::Raid1c3), options.metadata_profile());
+    let options = Formatter::options().profile(DataProfile::Dup).unwrap();
+    assert_eq!(Some(DataProfile::Dup), options.data_profile());
+    assert_eq!(Some(DataProfile::Dup), options.metadata_profile());
+}
+
+#[test]
+fn uuid_modes() {
+    let uuid = |options: FormatterOptions| -> String {
+        let args = options.to_args();
+        let arg = args[0].to_str().unwrap();
+        let uuid = arg.strip_prefix("--uuid=").expect("The only arg is a uuid");
+        Formatter::options()
+            .uuid(uuid)
+            .expect("Generated UUIDs should be valid");
+        uuid.to_owned()
+    };
+    let random = uuid(Formatter::options().uuid_random().unwrap());
+    assert_eq!(
+        Some('4'),
+        random.chars().nth(14),
+        "{random} is not version 4"
+    );
+    assert_ne!(random, uuid(Formatter::options().uuid_random().unwrap()));
+    let time = uuid(Formatter::options().uuid_mode(UuidMode::Time).unwrap());
+    assert_eq!(Some('1'), time.chars().nth(14), "{time} is not version 1");
+}
+
+#[test]
+#[cfg(feature = "log")]
+fn rejected_values_are_logged() {
+    use std::sync::Mutex;
+    static LOGGED: Mutex<Vec<String>> = Mutex::new(Vec::new());
+    crate::log::set_hook(|rejected| {
+        LOGGED
+            .lock()
+            .unwrap()
+            .push(format!("{} = {}", rejected.option, rejected.value))
+    });
+    assert!(Formatter::options().nodesize(12345).is_err());
+    // Other tests may log too, while the hook is installed
+    assert!(LOGGED
+        .lock()
+        .unwrap()
+        .contains(&String::from("nodesize = 12345")));
+}
+
+#[test]
+fn validate() {
+    let rejected_option = |options: FormatterOptions| match options.validate() {
+        Err(crate::Error::ArgumentError { option, .. }) => option,
+        other => panic!("Expected an ArgumentError, got {other:?}"),
+    };
+    Formatter::options()
+        .nodesize(16384)
+        .and_then(|options| options.sectorsize(4096))
+        .and_then(|options| options.profile(DataProfile::Dup))
+        .unwrap()
+        .validate()
+        .expect("A consistent config is valid");
+    let options = Formatter::options().sectorsize(2048).unwrap();
+    assert_eq!("sectorsize", rejected_option(options));
+    let options = Formatter::options()
+        .nodesize(4096)
+        .and_then(|options| options.sectorsize(8192))
+        .unwrap();
+    assert_eq!("nodesize", rejected_option(options));
+    let options = Formatter::options()
+        .mixed()
+        .and_then(|options| options.data(DataProfile::Single))
+        .and_then(|options| options.metadata(DataProfile::Dup))
+        .unwrap();
+    assert_eq!("mixed", rejected_option(options));
+}
+
+#[test]
+fn violations() {
+    use crate::validate::Violation;
+    assert!(Formatter::options().violations().is_empty());
+    let options = Formatter::options()
+        .mixed()
+        .and_then(|options| options.data(DataProfile::Single))
+        .and_then(|options| options.metadata(DataProfile::Dup))
+        .and_then(|options| options.nodesize(16384))
+        .and_then(|options| options.sectorsize(4096))
+        .and_then(|options| options.shrink())
+        .unwrap();
+    assert_eq!(
+        vec![
+            Violation::ShrinkWithoutRootdir,
+            Violation::MixedProfiles {
+                data: DataProfile::Single,
+                metadata: DataProfile::Dup
+            },
+            Violation::MixedBlockSizes {
+                nodesize: 16384,
+                sectorsize: 4096
+            },
+        ],
+        options.violations()
+    );
+    // validate reports the first of them
+    match options.validate() {
+        Err(crate::Error::ArgumentError { option, message }) => {
+            assert_eq!("shrink", option);
+            assert_eq!(Violation::ShrinkWithoutRootdir.to_string(), message);
+        }
+        other => panic!("Expected an ArgumentError, got {other:?}"),
+    }
+}
+
+#[test]
+fn base_dir() {
+    let base = scratch_dir().join("base_dir");
+    fs::create_dir_all(base.join("root")).unwrap();
+    let options = Formatter::options()
+        .base_dir(&base)
+        .and_then(|options| options.rootdir("root"))
+        .unwrap();
+    let expected = fs::canonicalize(&base).unwrap().join("root");
+    assert_eq!(
+        vec![OsString::from(format!("--rootdir={}", expected.display()))],
+        options.to_args()
+    );
+    Formatter::options()
+        .base_dir(base.join("missing"))
+        .expect_err("The base dir must exist");
+}
+
+#[test]
+fn block_sizes() {
+    for size in [1, 4096, 16384] {
+        Formatter::options()
+            .nodesize(size)
+            .expect("Powers of 2 up to MAX_NODESIZE are fine");
+    }
+    for size in [0, 12288, MAX_NODESIZE * 2] {
+        Formatter::options()
+            .nodesize(size)
+            .expect_err("Not a power of 2, or too big");
+    }
+    for size in [4096, 65536] {
+        Formatter::options()
+            .sectorsize(size)
+            .expect("Powers of 2 up to MAX_SECTORSIZE are fine");
+    }
+    for size in [0, 3000, MAX_SECTORSIZE * 2] {
+        Formatter::options()
+            .sectorsize(size)
+            .expect_err("Not a power of 2, or too big");
+    }
+}
+
+#[test]
+fn iter_args() {
+    let options = Formatter::options()
+        .label("iterated")
+        .and_then(|options| options.force())
+        .unwrap();
+    let formatter = options.build();
+    let args: Vec<&std::ffi::OsStr> = formatter.iter_args().collect();
+    assert_eq!(vec!["--force", "--label=iterated"], args);
+}
+
+#[test]
+#[cfg(unix)]
+fn enable_if_supported() {
+    let binary = stub(
+        "enable_if_supported",
+        "echo 'Filesystem features available:'\n\
+         echo 'no-holes            - no explicit hole extents for files'",
+    );
+    let options = Formatter::options()
+        .features(["mixed-bg"])
+        .and_then(|options| options.enable_if_supported_by("no-holes", &binary))
+        .and_then(|options| options.enable_if_supported_by("block-group-tree", &binary))
+        .unwrap();
+    assert_eq!(
+        vec![OsString::from("--features=mixed-bg,no-holes")],
+        options.to_args()
+    );
+}
+
+#[test]
+fn uuids_from_seed() {
+    let args = Formatter::options().uuids_from_seed(42).unwrap().to_args();
+    // Pinned, so a change to the derivation can't slip by unnoticed
+    assert_eq!(
+        vec![
+            OsString::from("--device-uuid=47526757-130f-4f52-981c-e1ff0e4ae394"),
+            OsString::from("--uuid=bdd73226-2feb-4e95-a8ef-e333b266f103"),
+        ],
+        args
+    );
+    assert_ne!(
+        args,
+        Formatter::options().uuids_from_seed(43).unwrap().to_args()
+    );
+}
+
+#[test]
+fn device_uuid_matches_uuid() {
+    use crate::validate::Violation;
+    let uuid = "73e1b7e2-a3a8-49c2-b258-06f01a889bba";
+    let options = Formatter::options()
+        .uuid(uuid)
+        .and_then(|options| options.device_uuid(&uuid.to_uppercase()))
+        .unwrap();
+    assert_eq!(
+        vec![Violation::DeviceUuidMatchesUuid(uuid.to_uppercase())],
+        options.violations()
+    );
+    options.try_build().expect_err("The UUIDs are the same");
+    Formatter::options()
+        .uuid(uuid)
+        .and_then(|options| options.device_uuid("2b0b4b8e-4d4b-4f5b-9a53-396e3145e1fa"))
+        .and_then(|options| options.try_build())
+        .expect("The UUIDs are distinct");
+}
+
+#[test]
+fn shrink_without_rootdir() {
+    Formatter::options()
+        .rootdir("./src")
+        .and_then(|options| options.shrink())
+        .unwrap()
+        .try_build()
+        .expect("shrink works with a rootdir");
+    let err = Formatter::options()
+        .shrink()
+        .unwrap()
+        .try_build()
+        .expect_err("shrink does nothing without a rootdir");
+    assert!(matches!(
+        err,
+        crate::Error::ArgumentError {
+            option: "shrink",
+            ..
+        }
+    ));
+    let warned = Formatter::options()
+        .shrink()
+        .and_then(|options| options.shrink_check(Strictness::Warn))
+        .unwrap()
+        .try_build()
+        .expect("Only warned about");
+    let device = super::scratch_file("shrink_without_rootdir");
+    let preflight = warned.preflight(&device).unwrap();
+    assert!(preflight
+        .warnings
+        .iter()
+        .any(|warning| warning.contains("shrink")));
+}
+
+#[test]
+fn presets() {
+    let args = |preset| Formatter::options().preset(preset).unwrap().to_args();
+    assert_eq!(
+        vec![
+            "--data=single",
+            "--metadata=single",
+            "--mixed",
+            "--nodesize=4096",
+            "--sectorsize=4096",
+        ],
+        args(Preset::Minimal)
+    );
+    assert_eq!(
+        vec![
+            "--checksum=xxhash",
+            "--features=no-holes,free-space-tree,block-group-tree",
+            "--metadata=dup",
+            "--nodesize=16384",
+        ],
+        args(Preset::Server)
+    );
+    assert_eq!(
+        vec![
+            "--checksum=crc32c",
+            "--features=no-holes,free-space-tree",
+            "--nodesize=16384",
+        ],
+        args(Preset::SsdOptimized)
+    );
+    assert_eq!(
+        vec![
+            "--checksum=crc32c",
+            "--features=^no-holes,^free-space-tree",
+            "--nodiscard",
+            "--nodesize=16384",
+        ],
+        args(Preset::Compatibility)
+    );
+    Formatter::options()
+        .preset(Preset::Minimal)
+        .unwrap()
+        .try_build()
+        .expect("Presets are consistent");
+}
+
+#[test]
+fn preset_from_str() {
+    for preset in Preset::ALL {
+        assert_eq!(preset, preset.to_string().parse::<Preset>().unwrap());
+    }
+    assert_eq!(Preset::SsdOptimized, "SSD-Optimized".parse().unwrap());
+    match "ssd".parse::<Preset>() {
+        Err(crate::Error::ArgumentError {
+            option: "preset",
+            message,
+        }) => assert!(
+            message.contains("Must be one of minimal, server, ssd-optimized, compatibility"),
+            "{message}"
+        ),
+        other => panic!("ssd is not a preset: {other:?}"),
+    }
+}
+
+#[test]
+fn extra_args() {
+    let options = Formatter::options()
+        .label("extra")
+        .and_then(|options| options.extra_arg("--quiet"))
+        .and_then(|options| options.extra_args(["--subvol", "home"]))
+        .unwrap();
+    assert_eq!(
+        vec!["--label=extra", "--quiet", "--subvol", "home"],
+        options.to_args()
+    );
+    assert_eq!(vec!["--label=extra"], options.clear_extra_args().to_args());
+}
+
+#[test]
+fn errors_link_to_manual() {
+    let err = Formatter::options()
+        .nodesize(3000)
+        .expect_err("3000 is not a power of 2");
+    assert!(
+        err.to_string().contains(&format!("{MANUAL_BASE}#options")),
+        "{err}"
+    );
+    let err = "raid7"
+        .parse::<DataProfile>()
+        .expect_err("raid7 is not a profile");
+    assert!(
+        err.to_string().contains(&format!("{MANUAL_BASE}#profiles")),
+        "{err}"
+    );
+}
+
+#[test]
+fn describe() {
+    let summary = Formatter::options()
+        .data(DataProfile::Raid1)
+        .and_then(|options| options.metadata(DataProfile::Raid1c3))
+        .and_then(|options| options.checksum(ChecksumAlgorithm::Blake2))
+        .and_then(|options| options.byte_count(1 << 30))
+        .and_then(|options| options.nodesize(16384))
+        .and_then(|options| options.features([MkfsFeature::NoHoles, MkfsFeature::Quota]))
+        .unwrap()
+        .build()
+        .describe();
+    assert_eq!(
+        FormatterSummary {
+            data: Some(DataProfile::Raid1),
+            metadata: Some(DataProfile::Raid1c3),
+            checksum: Some(ChecksumAlgorithm::Blake2),
+            byte_count: Some(1 << 30),
+            nodesize: Some(16384),
+            sectorsize: None,
+            features: vec![String::from("no-holes"), String::from("quota")],
+        },
+        summary
+    );
+    assert_eq!(
+        "data: raid1\nmetadata: raid1c3\nchecksum: blake2\nbyte_count: 1073741824\n\
+         nodesize: 16384\nfeatures: no-holes,quota\n",
+        summary.to_string()
+    );
+    assert_eq!(
+        FormatterSummary::default(),
+        Formatter::options().build().describe()
+    );
+}
+
+#[test]
+fn superblock_offsets() {
+    let offsets = |options: FormatterOptions| options.build().superblock_offsets();
+    assert_eq!(
+        vec![65536, 67108864, 274877906944],
+        offsets(Formatter::options())
+    );
+    assert_eq!(
+        vec![65536],
+        offsets(Formatter::options().byte_count(64 << 20).unwrap())
+    );
+    assert_eq!(
+        vec![65536, 67108864],
+        offsets(Formatter::options().byte_count((64 << 20) + 4096).unwrap())
+    );
+    assert_eq!(
+        SUPERBLOCK_OFFSETS.to_vec(),
+        offsets(Formatter::options().byte_count(1 << 40).unwrap())
+    );
+}
+
+#[test]
+fn add_features() {
+    let features = |options: FormatterOptions| {
+        options
+            .build()
+            .iter_args()
+            .find(|arg| arg.to_string_lossy().starts_with("--features="))
+            .map(|arg| arg.to_os_string())
+    };
+    let options = Formatter::options()
+        .add_features([MkfsFeature::NoHoles, MkfsFeature::Quota])
+        .and_then(|options| options.add_features(["block-group-tree", "quota"]))
+        .unwrap();
+    assert_eq!(
+        Some(OsString::from("--features=no-holes,quota,block-group-tree")),
+        features(options.clone())
+    );
+    let options = options
+        .add_features([MkfsFeature::NoHoles.disable()])
+        .unwrap();
+    assert_eq!(
+        Some(OsString::from(
+            "--features=quota,block-group-tree,^no-holes"
+        )),
+        features(options.clone())
+    );
+    // features still replaces the whole list
+    assert_eq!(
+        Some(OsString::from("--features=raid56")),
+        features(options.features(["raid56"]).unwrap())
+    );
+}
+
+#[test]
+fn disable_features() {
+    let options = Formatter::options()
+        .add_features([MkfsFeature::Quota])
+        .and_then(|options| options.disable_features(["no-holes", "free-space-tree"]))
+        .unwrap();
+    assert!(options.to_args().contains(&OsString::from(
+        "--features=quota,^no-holes,^free-space-tree"
+    )));
+    // Disabling twice is fine
+    options.clone().disable_features(["no-holes"]).unwrap();
+    let err = options
+        .clone()
+        .disable_features(["quota"])
+        .expect_err("quota is enabled");
+    assert!(matches!(
+        err,
+        crate::Error::ArgumentError {
+            option: "features",
+            ..
+        }
+    ));
+    options
+        .disable_features(["^quota"])
+        .expect_err("The name already has a '^'");
+}
+
+#[test]
+fn byte_count_too_small() {
+    use crate::validate::Violation;
+    let options = Formatter::options()
+        .profile(DataProfile::Dup)
+        .and_then(|options| options.byte_count(64 << 20))
+        .unwrap();
+    assert_eq!(
+        vec![Violation::ByteCountTooSmall {
+            byte_count: 64 << 20,
+            profile: DataProfile::Dup,
+            minimum: MIN_BYTE_COUNT_DUP,
+        }],
+        options.violations()
+    );
+    assert!(matches!(
+        options.try_build(),
+        Err(crate::Error::ArgumentError {
+            option: "byte_count",
+            ..
+        })
+    ));
+    Formatter::options()
+        .profile(DataProfile::Dup)
+        .and_then(|options| options.byte_count(1 << 30))
+        .and_then(|options| options.try_build())
+        .expect("1GiB is plenty for dup");
+    Formatter::options()
+        .profile(DataProfile::Single)
+        .and_then(|options| options.byte_count(64 << 20))
+        .and_then(|options| options.try_build())
+        .expect("single needs less room than dup");
+    // mkfs.btrfs defaults to dup metadata, so that's the minimum without a profile
+    let options = Formatter::options().byte_count(1 << 20).unwrap();
+    assert_eq!(
+        vec![Violation::ByteCountTooSmall {
+            byte_count: 1 << 20,
+            profile: DataProfile::Dup,
+            minimum: MIN_BYTE_COUNT_DUP,
+        }],
+        options.violations()
+    );
+    Formatter::options()
+        .data(DataProfile::Single)
+        .and_then(|options| options.byte_count(64 << 20))
+        .and_then(|options| options.try_build())
+        .expect_err("metadata is still dup");
+    Formatter::options()
+        .mixed()
+        .and_then(|options| options.profile(DataProfile::Dup))
+        .and_then(|options| options.byte_count(64 << 20))
+        .and_then(|options| options.try_build())
+        .expect("mixed mode fits in less");
+}
+
+#[test]
+fn diff() {
+    let old = Formatter::options()
+        .label("before")
+        .and_then(|options| options.force())
+        .unwrap();
+    let new = old
+        .clone()
+        .label("after")
+        .and_then(|options| options.checksum(ChecksumAlgorithm::XxHash))
+        .unwrap();
+    assert_eq!(
+        vec![
+            FieldChange {
+                field: Field::Checksum,
+                old: None,
+                new: Some(OsString::from("--checksum=xxhash")),
+            },
+            FieldChange {
+                field: Field::Label,
+                old: Some(OsString::from("--label=before")),
+                new: Some(OsString::from("--label=after")),
+            },
+        ],
+        old.diff(&new)
+    );
+    assert!(old.diff(&old).is_empty());
+}
+
+#[test]
+#[cfg(all(unix, feature = "tar"))]
+fn rootdir_from_tar() {
+    use crate::format::OutputExt;
+    let content = scratch_dir().join("rootdir_from_tar");
+    fs::create_dir_all(&content).unwrap();
+    fs::write(content.join("hello.txt"), "hello").unwrap();
+    let archive = scratch_dir().join("rootdir_from_tar.tar");
+    let tar = std::process::Command::new("tar")
+        .arg("-cf")
+        .arg(&archive)
+        .arg("-C")
+        .arg(&content)
+        .arg("hello.txt")
+        .status()
+        .unwrap();
+    assert!(tar.success());
+
+    let options = Formatter::options().rootdir_from_tar(&archive).unwrap();
+    let rootdir = options
+        .build()
+        .iter_args()
+        .find_map(|arg| {
+            arg.to_str()?
+                .strip_prefix("--rootdir=")
+                .map(std::path::PathBuf::from)
+        })
+        .expect("rootdir is set");
+    assert_eq!(
+        "hello",
+        fs::read_to_string(rootdir.join("hello.txt")).unwrap()
+    );
+    let output = options
+        .build()
+        .with_binary(stub(
+            "rootdir_from_tar",
+            r#"cat "${1#--rootdir=}/hello.txt""#,
+        ))
+        .format(super::scratch_file("rootdir_from_tar.img"))
+        .unwrap();
+    assert_eq!("hello", output.stdout_str());
+    drop(options);
+    assert!(!rootdir.exists(), "The extracted files are cleaned up");
+
+    let err = Formatter::options()
+        .rootdir_from_tar(content.join("hello.txt"))
+        .expect_err("Not a tar archive");
+    assert!(matches!(
+        err,
+        crate::Error::ArgumentError {
+            option: "rootdir",
+            ..
+        }
+    ));
+}
+
+#[test]
+#[cfg(feature = "tempfile")]
+fn rootdirs() {
+    use crate::format::ConflictPolicy;
+    let first = scratch_dir().join("rootdirs_first");
+    let second = scratch_dir().join("rootdirs_second");
+    for (dir, name) in [(&first, "first"), (&second, "second")] {
+        fs::create_dir_all(dir.join("nested")).unwrap();
+        fs::write(dir.join("shared.txt"), name).unwrap();
+        fs::write(dir.join(format!("{name}.txt")), name).unwrap();
+        fs::write(dir.join("nested").join(format!("{name}.txt")), name).unwrap();
+    }
+    let merged = |policy| {
+        let options = Formatter::options().rootdirs([&first, &second], policy)?;
+        let rootdir = options
+            .to_args()
+            .iter()
+            .find_map(|arg| {
+                arg.to_str()?
+                    .strip_prefix("--rootdir=")
+                    .map(std::path::PathBuf::from)
+            })
+            .expect("rootdir is set");
+        Ok::<_, crate::Error>((options, rootdir))
+    };
+
+    for (policy, shared) in [
+        (ConflictPolicy::FirstWins, "first"),
+        (ConflictPolicy::LastWins, "second"),
+    ] {
+        let (options, rootdir) = merged(policy).unwrap();
+        let read = |path: &str| fs::read_to_string(rootdir.join(path)).unwrap();
+        assert_eq!(shared, read("shared.txt"), "{policy:?}");
+        assert_eq!("first", read("first.txt"));
+        assert_eq!("second", read("second.txt"));
+        assert_eq!("first", read("nested/first.txt"));
+        assert_eq!("second", read("nested/second.txt"));
+        drop(options);
+        assert!(!rootdir.exists(), "The staged files are cleaned up");
+    }
+    let err = merged(ConflictPolicy::Error).expect_err("shared.txt is in both");
+    assert!(
+        matches!(
+            &err,
+            crate::Error::ArgumentError {
+                option: "rootdirs",
+                message,
+            } if message.contains("shared.txt")
+        ),
+        "{err:?}"
+    );
+    Formatter::options()
+        .rootdirs(
+            [&first, &scratch_dir().join("rootdirs_missing")],
+            ConflictPolicy::LastWins,
+        )
+        .expect_err("Every rootdir must exist");
+}
+
+#[test]
+fn try_from_args() {
+    let options = Formatter::options()
+        .byte_count(1 << 30)
+        .and_then(|options| options.checksum(ChecksumAlgorithm::XxHash))
+        .and_then(|options| options.data(DataProfile::Single))
+        .and_then(|options| options.metadata(DataProfile::Dup))
+        .and_then(|options| options.features([MkfsFeature::NoHoles, MkfsFeature::Quota]))
+        .and_then(|options| options.force())
+        .and_then(|options| options.label("round trip"))
+        .and_then(|options| options.nodesize(16384))
+        .and_then(|options| options.sectorsize(4096))
+        .and_then(|options| options.rootdir("src"))
+        .and_then(|options| options.shrink())
+        .and_then(|options| options.uuid("0f5a83a2-94b3-4c1c-b5e0-9b3b5e2b1f3d"))
+        .unwrap();
+    let args: Vec<OsString> = options.build().iter_args().map(OsString::from).collect();
+    assert_eq!(
+        options,
+        FormatterOptions::try_from(args.as_slice()).unwrap()
+    );
+
+    let imported = FormatterOptions::try_from(
+        [OsString::from("--mixed"), OsString::from("--quiet")].as_slice(),
+    )
+    .unwrap();
+    assert_eq!(
+        vec!["--mixed", "--quiet"],
+        imported.build().iter_args().collect::<Vec<_>>()
+    );
+    match FormatterOptions::try_from([OsString::from("--nodesize=lots")].as_slice()) {
+        Err(crate::Error::ArgumentError {
+            option: "nodesize",
+            message,
+        }) => assert!(message.starts_with("--nodesize ( = lots )"), "{message}"),
+        other => panic!("{other:?}"),
+    }
+}
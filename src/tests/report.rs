@@ -0,0 +1,265 @@
+//! Tests for parsing mkfs.btrfs output
+
+use crate::{format::Formatter, report::*, Error};
+
+#[test]
+fn shrunk_size() {
+    let stdout = "\
+Rootdir from:       src
+  Shrink:           yes
+Label:              (null)
+Filesystem size:    114.00MiB
+";
+    let report = FormatReport::parse(stdout);
+    assert_eq!(Some(114 * 1024 * 1024), report.shrunk_size_bytes);
+}
+
+#[test]
+fn not_shrunk() {
+    let report = FormatReport::parse("Filesystem size:    512.00MiB\n");
+    assert_eq!(None, report.shrunk_size_bytes);
+}
+
+#[test]
+fn size_units() {
+    assert_eq!(Some(512), parse_size("512.00B"));
+    assert_eq!(Some(1536), parse_size("1.50KiB"));
+    assert_eq!(Some(3 << 30), parse_size("3.00GiB"));
+    assert_eq!(Some(1_500), parse_size("1.50kB"));
+    assert_eq!(Some(119_540_000), parse_size("119.54MB"));
+    assert_eq!(Some(2_000_000_000_000), parse_size("2.00TB"));
+    assert_eq!(None, parse_size("3.00 bananas"));
+}
+
+#[test]
+fn header() {
+    use crate::format::DataProfile;
+    let stdout = "\
+btrfs-progs v6.6.3
+See https://btrfs.readthedocs.io for more information.
+
+Performing full device TRIM test.img (1.00GiB) ...
+NOTE: several default settings have changed in version 5.15, please make sure
+      this does not affect your deployments:
+      - DUP for metadata (-m dup)
+      - enabled no-holes (-O no-holes)
+      - enabled free-space-tree (-R free-space-tree)
+
+Label:              (null)
+UUID:               3b5a4f0e-8a57-4b55-8c4b-2b3c0f1e9d2a
+Node size:          16384
+Sector size:        4096	(CPU page size: 4096)
+Filesystem size:    1.00GiB
+Block group profiles:
+  Data:             single            8.00MiB
+  Metadata:         DUP              51.19MiB
+  System:           DUP               8.00MiB
+SSD detected:       no
+Zoned device:       no
+Incompat features:  extref, skinny-metadata, no-holes, free-space-tree
+Runtime features:   free-space-tree
+Checksum:           crc32c
+Number of devices:  1
+Devices:
+   ID        SIZE  PATH
+    1     1.00GiB  test.img
+
+";
+    let report = FormatReport::parse(stdout);
+    assert_eq!(Some(1 << 30), report.filesystem_size_bytes);
+    assert_eq!(None, report.shrunk_size_bytes, "Not shrunk");
+    assert_eq!(Some(16384), report.node_size);
+    assert_eq!(Some(4096), report.sector_size);
+    assert_eq!(Some(1), report.number_of_devices);
+    assert_eq!(1, report.device_count());
+    assert_eq!(
+        Some(BlockGroup {
+            profile: DataProfile::Single,
+            size_bytes: 8 << 20,
+        }),
+        report.data
+    );
+    assert_eq!(
+        Some(BlockGroup {
+            profile: DataProfile::Dup,
+            size_bytes: parse_size("51.19MiB").unwrap(),
+        }),
+        report.metadata
+    );
+    assert_eq!(
+        Some(DataProfile::Dup),
+        report.system.map(|group| group.profile)
+    );
+    assert!(report.free_space_tree);
+
+    let si = FormatReport::parse(
+        "Filesystem size:    1.07GB
+  Data:   RAID1   8.39MB
+",
+    );
+    assert_eq!(Some(1_070_000_000), si.filesystem_size_bytes);
+    assert_eq!(Some(8_390_000), si.data.map(|group| group.size_bytes));
+}
+
+#[test]
+fn format_shrunk_requires_shrink() {
+    let err = Formatter::options()
+        .build()
+        .format_shrunk("/tmp/does-not-matter.btrfs")
+        .expect_err("shrink was not requested");
+    assert!(matches!(err, Error::ArgumentError { .. }));
+}
+
+#[test]
+fn sector_size() {
+    let report = FormatReport::parse("Node size:          16384\nSector size:        4096\n");
+    assert_eq!(Some(4096), report.sector_size);
+    assert_eq!(None, report.sector_size_warning());
+
+    let report = FormatReport::parse("Sector size:        65536\n");
+    assert_eq!(Some(65536), report.sector_size);
+    assert!(report.sector_size_warning().is_some());
+
+    assert_eq!(None, FormatReport::parse("").sector_size_warning());
+
+    let newer = FormatReport::parse("Sector size:        4096\t(CPU page size: 4096)\n");
+    assert_eq!(Some(4096), newer.sector_size);
+}
+
+#[test]
+fn feature_list() {
+    let list_all = "\
+Filesystem features available:
+mixed-bg            - mixed data and metadata block groups (compat=2.6.37, safe=2.6.37)
+extref              - increased hardlink limit per file to 65536 (compat=3.7, safe=3.12, default=3.12)
+raid56              - raid56 extended format (compat=3.9)
+skinny-metadata     - reduced-size metadata extent refs (compat=3.10, safe=3.18, default=3.18)
+no-holes            - no explicit hole extents for files (compat=3.14, safe=4.0, default=5.15)
+fst                 - alias for free-space-tree
+free-space-tree     - free space tree, improved space tracking (space_cache=v2) (compat=4.5, safe=4.9, default=5.15)
+";
+    assert_eq!(
+        vec![
+            "mixed-bg",
+            "extref",
+            "raid56",
+            "skinny-metadata",
+            "no-holes",
+            "fst",
+            "free-space-tree"
+        ],
+        parse_feature_list(list_all)
+    );
+}
+
+#[test]
+fn verify_features() {
+    let supported = parse_feature_list("no-holes - no explicit hole extents for files\n");
+    Formatter::options()
+        .features(["^no-holes"])
+        .and_then(|options| options.verify_features(&supported))
+        .expect("no-holes is supported");
+    Formatter::options()
+        .features(["no-holes", "raid56"])
+        .and_then(|options| options.verify_features(&supported))
+        .expect_err("raid56 isn't supported");
+}
+
+#[test]
+fn devices() {
+    let stdout = "\
+Number of devices:  3
+Devices:
+   ID        SIZE  PATH
+    1    16.00GiB  /dev/sdb
+    2    16.00GiB  /dev/sdc
+    3    16.00GiB  /dev/sdd
+
+";
+    let report = FormatReport::parse(stdout);
+    assert_eq!(3, report.device_count());
+    assert_eq!(48 << 30, report.total_size_bytes());
+    assert_eq!(
+        DeviceInfo {
+            id: 2,
+            size_bytes: 16 << 30,
+            path: std::path::PathBuf::from("/dev/sdc"),
+        },
+        report.devices[1]
+    );
+    assert_eq!(0, FormatReport::parse("").device_count());
+}
+
+#[test]
+#[cfg(feature = "json")]
+fn to_json() {
+    let stdout = "\
+Rootdir from:       src
+  Shrink:           yes
+Filesystem size:    114.00MiB
+Sector size:        4096
+Devices:
+   ID        SIZE  PATH
+    1   114.00MiB  /tmp/\"quoted\".img
+";
+    let json = FormatReport::parse(stdout).to_json();
+    assert!(json.contains(r#""shrunk_size_bytes":119537664"#), "{json}");
+    assert!(json.contains(r#""sector_size":4096"#), "{json}");
+    assert!(
+        json.contains(r#""filesystem_size_bytes":119537664"#),
+        "{json}"
+    );
+    assert!(json.contains(r#""free_space_tree":false"#), "{json}");
+    assert!(json.contains(r#""device_size_bytes":null"#), "{json}");
+    assert!(
+        json.contains(
+            r#""devices":[{"id":1,"size_bytes":119537664,"path":"/tmp/\"quoted\".img"}]"#
+        ),
+        "{json}"
+    );
+}
+
+#[test]
+fn free_space_tree() {
+    let stdout = "\
+Label:              (null)
+Node size:          16384
+Sector size:        4096
+Filesystem size:    512.00MiB
+Block group profiles:
+  Data:             single            8.00MiB
+Runtime features:   free-space-tree
+Checksum:           crc32c
+";
+    assert!(FormatReport::parse(stdout).free_space_tree);
+    let newer = "Features:           extref, skinny-metadata, no-holes, free-space-tree\n";
+    assert!(FormatReport::parse(newer).free_space_tree);
+    let without = "Features:           extref, skinny-metadata, no-holes\n";
+    assert!(!FormatReport::parse(without).free_space_tree);
+}
+
+#[test]
+fn csum_type() {
+    use crate::format::ChecksumAlgorithm;
+    let dump = "\
+superblock: bytenr=65536, device=test.btrfs
+---------------------------------------------------------
+csum_type		1 (xxhash64)
+csum_size		8
+csum			0x3b2c2ad0e4e5c9d1 [match]
+bytenr			65536
+flags			0x1
+			( WRITTEN )
+magic			_BHRfS_M [match]
+";
+    assert_eq!(Some(ChecksumAlgorithm::XxHash), parse_csum_type(dump));
+    assert_eq!(
+        Some(ChecksumAlgorithm::Blake2),
+        parse_csum_type("csum_type\t\t3 (blake2b)\n")
+    );
+    // `csum` and `csum_size` aren't mistaken for it
+    assert_eq!(
+        None,
+        parse_csum_type("csum_size\t\t8\ncsum\t\t\t0x0 [match]\n")
+    );
+}
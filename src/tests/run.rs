@@ -0,0 +1,995 @@
+//! Tests for running mkfs.btrfs, using stubs in its place
+
+use super::{scratch_dir, scratch_file, stub};
+use crate::{
+    format::{Formatter, MkfsFeature, OutputExt},
+    Error,
+};
+use std::time::{Duration, Instant};
+
+#[test]
+fn timeout_expires() {
+    let binary = stub("sleepy", "exec sleep 5");
+    let start = Instant::now();
+    let err = Formatter::options()
+        .build()
+        .with_binary(binary)
+        .format_timeout(scratch_file("timeout_expires"), Duration::from_millis(100))
+        .expect_err("The stub sleeps longer than the timeout");
+    assert!(matches!(err, Error::Timeout(_)), "{err:?}");
+    assert!(start.elapsed() < Duration::from_secs(5));
+}
+
+#[test]
+fn timeout_not_reached() {
+    let binary = stub("quick", "echo \"formatted $1\"");
+    let device = scratch_file("timeout_not_reached");
+    let output = Formatter::options()
+        .build()
+        .with_binary(binary)
+        .format_timeout(&device, Duration::from_secs(5))
+        .expect("The stub exits immediately");
+    assert!(output.status.success());
+    assert_eq!(
+        format!("formatted {}\n", device.display()),
+        output.stdout_str()
+    );
+}
+
+#[test]
+fn callbacks() {
+    let binary = stub(
+        "chatty",
+        "for i in 1 2 3; do echo \"out $i\"; echo \"err $i\" >&2; done; exit 3",
+    );
+    let (mut stdout, mut stderr) = (vec![], vec![]);
+    let status = Formatter::options()
+        .build()
+        .with_binary(binary)
+        .format_with_callbacks(
+            scratch_file("callbacks"),
+            |line| stdout.push(line.to_owned()),
+            |line| stderr.push(line.to_owned()),
+        )
+        .expect("The stub should run");
+    assert_eq!(Some(3), status.code());
+    assert_eq!(vec!["out 1", "out 2", "out 3"], stdout);
+    assert_eq!(vec!["err 1", "err 2", "err 3"], stderr);
+}
+
+#[test]
+fn format_is_repeatable() {
+    let formatter = Formatter::options()
+        .label("again")
+        .unwrap()
+        .build()
+        .with_binary(stub("echo_args", "echo \"$@\""));
+    let device = scratch_file("format_is_repeatable");
+    let first = formatter.format(&device).expect("The stub should run");
+    let second = formatter.format(&device).expect("The stub should run");
+    assert_eq!(
+        format!("--label=again {}\n", device.display()),
+        first.stdout_str()
+    );
+    assert_eq!(
+        format!("--label=again {}\n", device.display()),
+        second.stdout_str()
+    );
+}
+
+#[test]
+fn format_timed() {
+    let timed = Formatter::options()
+        .build()
+        .with_binary(stub("nap", "sleep 0.1"))
+        .format_timed(scratch_file("format_timed"))
+        .expect("The stub should run");
+    assert!(timed.output.status.success());
+    assert!(timed.elapsed >= Duration::from_millis(100));
+}
+
+#[test]
+fn byte_count_exceeds_file() {
+    let device = scratch_file("byte_count_exceeds_file");
+    std::fs::write(&device, vec![0; 4096]).unwrap();
+    let formatter = |byte_count| {
+        Formatter::options()
+            .byte_count(byte_count)
+            .unwrap()
+            .build()
+            .with_binary(stub("byte_count", "exit 0"))
+    };
+    let err = formatter(1 << 30)
+        .format(&device)
+        .expect_err("The file is only 4096 bytes long");
+    assert!(matches!(err, Error::ArgumentError { .. }), "{err:?}");
+    formatter(4096)
+        .format(&device)
+        .expect("The file is exactly byte_count bytes long");
+}
+
+#[test]
+fn missing_target() {
+    let device = scratch_dir().join("does/not/exist.btrfs");
+    let err = Formatter::options()
+        .build()
+        .with_binary(stub("missing_target", "exit 0"))
+        .format(&device)
+        .expect_err("The target doesn't exist");
+    assert_eq!(
+        format!(
+            "target path does not exist: {}; create the file or device first",
+            device.display()
+        ),
+        err.to_string()
+    );
+}
+
+#[cfg(feature = "tempfile")]
+#[test]
+fn format_tempfile() {
+    let (output, image) = Formatter::options()
+        .build()
+        .with_binary(stub("tempfile", "echo \"$1\""))
+        .format_tempfile(4096)
+        .expect("The stub should run");
+    let path = image.path().to_owned();
+    assert_eq!(format!("{}\n", path.display()), output.stdout_str());
+    assert_eq!(4096, path.metadata().unwrap().len());
+    drop(image);
+    assert!(!path.exists(), "The temp file should be removed on drop");
+}
+
+#[test]
+fn into_command() {
+    let device = scratch_file("into_command");
+    let command = Formatter::options()
+        .label("command")
+        .and_then(|options| options.force())
+        .unwrap()
+        .build()
+        .into_command(&device)
+        .expect("The device exists");
+    assert_eq!("mkfs.btrfs", command.get_program());
+    assert_eq!(
+        vec![
+            std::ffi::OsStr::new("--force"),
+            std::ffi::OsStr::new("--label=command"),
+            device.as_os_str(),
+        ],
+        command.get_args().collect::<Vec<_>>()
+    );
+}
+
+#[test]
+fn cancellation() {
+    let token = crate::format::CancellationToken::new();
+    let canceller = token.clone();
+    std::thread::spawn(move || {
+        std::thread::sleep(Duration::from_millis(100));
+        canceller.cancel();
+    });
+    let start = Instant::now();
+    let err = Formatter::options()
+        .build()
+        .with_binary(stub("cancellation", "exec sleep 5"))
+        .format_cancellable(scratch_file("cancellation"), &token)
+        .expect_err("The token is cancelled before the stub wakes up");
+    assert!(matches!(err, Error::Cancelled), "{err:?}");
+    assert!(token.is_cancelled());
+    assert!(start.elapsed() < Duration::from_secs(5));
+}
+
+#[test]
+fn format_batch() {
+    let devices = [
+        scratch_file("format_batch_a"),
+        scratch_dir().join("format_batch_missing"),
+        scratch_file("format_batch_c"),
+    ];
+    let results = Formatter::options()
+        .build()
+        .with_binary(stub("format_batch", "exit 0"))
+        .format_batch(&devices);
+    assert_eq!(3, results.len());
+    for ((device, result), expected) in results.iter().zip(&devices) {
+        assert_eq!(expected, device);
+        assert_eq!(device.exists(), result.is_ok(), "{device:?}: {result:?}");
+    }
+}
+
+#[test]
+fn format_batch_device_uuid() {
+    let devices = [
+        scratch_file("format_batch_device_uuid_a"),
+        scratch_file("format_batch_device_uuid_b"),
+    ];
+    let results = Formatter::options()
+        .device_uuid("2b0b4b8e-4d4b-4f5b-9a53-396e3145e1fa")
+        .unwrap()
+        .build()
+        .with_binary(stub("format_batch_device_uuid", "exit 0"))
+        .format_batch(&devices);
+    for (device, result) in &results {
+        assert!(
+            matches!(
+                result,
+                Err(Error::ArgumentError {
+                    option: "device_uuid",
+                    ..
+                })
+            ),
+            "{}: {result:?}",
+            device.display()
+        );
+    }
+}
+
+#[test]
+#[cfg(feature = "tempfile")]
+#[ignore = "needs mkfs.btrfs"]
+fn benchmark_checksums() -> crate::Result<()> {
+    let timings = Formatter::benchmark_checksums(crate::format::DEFAULT_IMAGE_SIZE)?;
+    assert!(!timings.is_empty());
+    Ok(())
+}
+
+#[test]
+fn output_str() {
+    use std::os::unix::process::ExitStatusExt;
+    let output = std::process::Output {
+        status: std::process::ExitStatus::from_raw(0),
+        stdout: b"Label:              (null)\n".to_vec(),
+        stderr: b"bad \xff byte".to_vec(),
+    };
+    assert_eq!("Label:              (null)\n", output.stdout_str());
+    assert_eq!("bad \u{fffd} byte", output.stderr_str());
+}
+
+#[test]
+fn experimental_profile() {
+    let refusal =
+        "echo 'ERROR: RAID5/6 is experimental and not supported by the running kernel' >&2; exit 1";
+    let err = Formatter::options()
+        .data(crate::DataProfile::Raid6)
+        .unwrap()
+        .build()
+        .with_binary(stub("experimental_profile", refusal))
+        .format_reported(scratch_file("experimental_profile"))
+        .expect_err("The stub refuses RAID5/6");
+    assert!(
+        matches!(
+            err,
+            Error::ExperimentalProfile {
+                profile: crate::DataProfile::Raid6
+            }
+        ),
+        "{err:?}"
+    );
+    let err = Formatter::options()
+        .build()
+        .with_binary(stub(
+            "other_failure",
+            "echo 'ERROR: device busy' >&2; exit 1",
+        ))
+        .format_reported(scratch_file("other_failure"))
+        .expect_err("The stub fails");
+    assert!(matches!(err, Error::CommandFailed { .. }), "{err:?}");
+}
+
+#[test]
+fn format_retry() {
+    let counter = scratch_dir().join("format_retry.count");
+    let _ = std::fs::remove_file(&counter);
+    let script = format!(
+        "n=$(cat '{0}' 2>/dev/null || echo 0)\n\
+         echo $((n + 1)) > '{0}'\n\
+         if [ \"$n\" -lt 2 ]; then echo \"ERROR: unable to open $1: Device or resource busy\" >&2; exit 1; fi\n\
+         echo formatted",
+        counter.display()
+    );
+    let output = Formatter::options()
+        .build()
+        .with_binary(stub("format_retry", &script))
+        .format_retry(scratch_file("format_retry"), 5, Duration::from_millis(1))
+        .expect("The stub should run");
+    assert!(output.status.success(), "{}", output.stderr_str());
+    assert_eq!("3\n", std::fs::read_to_string(&counter).unwrap());
+
+    let counter = scratch_dir().join("format_retry_fatal.count");
+    let _ = std::fs::remove_file(&counter);
+    let script = format!(
+        "n=$(cat '{0}' 2>/dev/null || echo 0)\n\
+         echo $((n + 1)) > '{0}'\n\
+         echo 'ERROR: not enough free space' >&2; exit 1",
+        counter.display()
+    );
+    let output = Formatter::options()
+        .build()
+        .with_binary(stub("format_retry_fatal", &script))
+        .format_retry(
+            scratch_file("format_retry_fatal"),
+            5,
+            Duration::from_millis(1),
+        )
+        .expect("The stub should run");
+    assert!(!output.status.success());
+    assert_eq!("1\n", std::fs::read_to_string(&counter).unwrap());
+}
+
+#[test]
+fn base_dir_device() {
+    scratch_file("base_dir_device");
+    let output = Formatter::options()
+        .base_dir(scratch_dir())
+        .unwrap()
+        .build()
+        .with_binary(stub("base_dir_device", "echo \"$1\""))
+        .format("base_dir_device")
+        .expect("The device resolves against the base dir");
+    assert_eq!(
+        format!("{}\n", scratch_dir().join("base_dir_device").display()),
+        output.stdout_str()
+    );
+}
+
+#[test]
+fn preflight() {
+    let device = scratch_file("preflight");
+    std::fs::File::options()
+        .write(true)
+        .open(&device)
+        .and_then(|file| file.set_len(1 << 20))
+        .unwrap();
+    let preflight = Formatter::options()
+        .build()
+        .preflight(&device)
+        .expect("The file exists");
+    assert_eq!(
+        crate::format::Preflight {
+            device,
+            kind: crate::format::TargetKind::File,
+            existing_filesystem: None,
+            force: false,
+            warnings: vec![],
+        },
+        preflight
+    );
+}
+
+#[test]
+fn skip_existence_check() {
+    let device = scratch_dir().join("does/not/exist.btrfs");
+    let output = Formatter::options()
+        .build()
+        .with_binary(stub("skip_existence_check", "echo \"$1\""))
+        .skip_existence_check()
+        .format(&device)
+        .expect("mkfs.btrfs runs, even for a missing target");
+    assert_eq!(format!("{}\n", device.display()), output.stdout_str());
+
+    // auto_discard can't probe a missing device, so it leaves discard alone
+    let output = Formatter::options()
+        .auto_discard()
+        .unwrap()
+        .build()
+        .with_binary(stub("skip_existence_check_auto_discard", r#"echo "$@""#))
+        .skip_existence_check()
+        .format(&device)
+        .expect("A missing device isn't probed");
+    assert_eq!(format!("{}\n", device.display()), output.stdout_str());
+}
+
+#[cfg(target_os = "linux")]
+#[test]
+fn page_size_mismatch() {
+    use crate::format::Strictness;
+    if crate::device::page_size() != 4096 {
+        return;
+    }
+    let options = Formatter::options().sectorsize(65536).unwrap();
+    let device = scratch_file("page_size_mismatch");
+    let preflight = options.build().preflight(&device).unwrap();
+    assert_eq!(1, preflight.warnings.len(), "{:?}", preflight.warnings);
+    options.validate().expect("Warnings don't fail validation");
+
+    let ignored = options.clone().page_size_check(Strictness::Ignore).unwrap();
+    assert!(ignored
+        .build()
+        .preflight(&device)
+        .unwrap()
+        .warnings
+        .is_empty());
+
+    let denied = options.page_size_check(Strictness::Deny).unwrap();
+    assert!(matches!(
+        denied.try_build(),
+        Err(Error::ArgumentError {
+            option: "sectorsize",
+            ..
+        })
+    ));
+}
+
+#[test]
+fn would_clobber() {
+    let device = scratch_file("would_clobber");
+    std::fs::write(&device, vec![0; 1 << 20]).unwrap();
+    let formatter = Formatter::options().build();
+    assert!(!formatter.would_clobber(&device).unwrap());
+    let mut image = std::fs::read(&device).unwrap();
+    image[0x1_0040..0x1_0048].copy_from_slice(b"_BHRfS_M");
+    std::fs::write(&device, image).unwrap();
+    assert!(formatter.would_clobber(&device).unwrap());
+}
+
+#[test]
+fn stdin_is_null() {
+    let started = Instant::now();
+    let output = Formatter::options()
+        .build()
+        .with_binary(stub("stdin_is_null", "cat; echo done"))
+        .format_timeout(scratch_file("stdin_is_null"), Duration::from_secs(5))
+        .expect("cat sees the end of stdin straight away");
+    assert_eq!("done\n", output.stdout_str());
+    assert!(started.elapsed() < Duration::from_secs(5));
+}
+
+#[test]
+fn with_stdin() {
+    let output = Formatter::options()
+        .build()
+        .with_binary(stub("with_stdin", "read answer; echo \"answered $answer\""))
+        .with_stdin("y\n")
+        .format(scratch_file("with_stdin"))
+        .expect("The stub reads its answer");
+    assert_eq!("answered y\n", output.stdout_str());
+}
+
+#[test]
+fn format_path() {
+    let device = scratch_file("format_path");
+    let formatted = Formatter::options()
+        .build()
+        .with_binary(stub("format_path", "exit 0"))
+        .format_path(&device)
+        .expect("The stub succeeds");
+    assert_eq!(device, formatted);
+    Formatter::options()
+        .build()
+        .with_binary(stub("format_path_fails", "exit 1"))
+        .format_path(&device)
+        .expect_err("The stub fails");
+}
+
+#[test]
+#[cfg(target_os = "linux")]
+fn format_fd_is_inherited() {
+    use std::os::unix::io::AsRawFd;
+    let device = std::fs::File::open(scratch_file("format_fd_is_inherited")).unwrap();
+    let formatter = Formatter::options().build().with_binary(stub(
+        "format_fd_is_inherited",
+        r#"for last; do :; done; test -e "$last" && echo "$last""#,
+    ));
+    let output = formatter.format_fd(device.as_raw_fd()).unwrap();
+    assert_eq!(
+        format!("/dev/fd/{}\n", device.as_raw_fd()),
+        output.stdout_str()
+    );
+    for fd in [-1, 1, 9999] {
+        assert!(matches!(
+            formatter.format_fd(fd),
+            Err(Error::ArgumentError { option: "fd", .. })
+        ));
+    }
+}
+
+#[test]
+#[cfg(target_os = "linux")]
+#[ignore = "needs mkfs.btrfs"]
+fn format_fd() -> crate::Result<()> {
+    use std::os::unix::io::AsRawFd;
+    let path = scratch_file("format_fd");
+    let device = std::fs::OpenOptions::new()
+        .read(true)
+        .write(true)
+        .open(&path)?;
+    device.set_len(crate::format::DEFAULT_IMAGE_SIZE)?;
+    let output = Formatter::options().build().format_fd(device.as_raw_fd())?;
+    assert!(output.status.success(), "{}", output.stderr_str());
+    assert_eq!(Some("btrfs"), crate::device::probe_filesystem(&path)?);
+    Ok(())
+}
+
+#[test]
+fn binary() {
+    use std::path::Path;
+    let formatter = Formatter::options().build();
+    assert_eq!(Path::new("mkfs.btrfs"), formatter.binary());
+    let stub = stub("binary", "exit 0");
+    let formatter = formatter.with_binary(&stub);
+    assert_eq!(stub, formatter.binary());
+}
+
+#[test]
+fn map_args() {
+    let formatter = Formatter::options()
+        .force()
+        .and_then(|options| options.label("mapped"))
+        .unwrap()
+        .build()
+        .map_args(|args| {
+            args.retain(|arg| arg != "--force");
+            args.push("--quiet".into());
+        })
+        .with_binary(stub("map_args", r#"echo "$@""#));
+    assert_eq!(
+        vec!["--label=mapped", "--quiet"],
+        formatter.iter_args().collect::<Vec<_>>()
+    );
+    let device = scratch_file("map_args");
+    let output = formatter.format(&device).unwrap();
+    assert_eq!(
+        format!("--label=mapped --quiet {}\n", device.display()),
+        output.stdout_str()
+    );
+}
+
+#[test]
+fn unsupported_option() {
+    for (name, complaint) in [
+        (
+            "unsupported_option_glibc",
+            "mkfs.btrfs: unrecognized option '--compress'",
+        ),
+        (
+            "unsupported_option_musl",
+            "mkfs.btrfs: unrecognized option: --compress",
+        ),
+    ] {
+        let err = Formatter::options()
+            .build()
+            .with_binary(stub(name, &format!("echo \"{complaint}\" >&2; exit 1")))
+            .format_path(scratch_file(name))
+            .expect_err("The stub rejects --compress");
+        assert!(
+            matches!(&err, Error::UnsupportedOption { option } if option == "--compress"),
+            "{err:?}"
+        );
+    }
+}
+
+#[test]
+fn format_preview() {
+    let binary = stub("format_preview", "exit 1");
+    let formatter = Formatter::options()
+        .label("My Volume")
+        .unwrap()
+        .build()
+        .with_binary(&binary);
+    let device = scratch_file("format_preview");
+    assert_eq!(
+        format!(
+            "{} '--label=My Volume' {}",
+            binary.display(),
+            device.display()
+        ),
+        formatter.format_preview(&device).unwrap()
+    );
+    let missing = scratch_dir().join("format_preview_missing");
+    assert!(matches!(
+        formatter.format_preview(missing),
+        Err(Error::ArgumentError {
+            option: "device",
+            ..
+        })
+    ));
+    assert!(matches!(
+        formatter.format_preview(scratch_dir()),
+        Err(Error::ArgumentError {
+            option: "device",
+            ..
+        })
+    ));
+}
+
+#[test]
+fn env() {
+    use std::ffi::OsStr;
+    let env = |options: crate::format::FormatterOptions| {
+        let command = options.build().into_command(scratch_file("env")).unwrap();
+        command
+            .get_envs()
+            .map(|(key, value)| (key.to_owned(), value.map(OsStr::to_owned)))
+            .collect::<Vec<_>>()
+    };
+    assert_eq!(
+        vec![
+            ("LANG".into(), Some("C".into())),
+            ("LC_ALL".into(), Some("C".into()))
+        ],
+        env(Formatter::options())
+    );
+    assert!(env(Formatter::options().native_locale().unwrap()).is_empty());
+    assert_eq!(
+        vec![
+            ("LANG".into(), Some("C".into())),
+            ("LC_ALL".into(), Some("de_DE.UTF-8".into())),
+            ("TMPDIR".into(), Some("/var/tmp".into())),
+        ],
+        env(Formatter::options()
+            .env("TMPDIR", "/var/tmp")
+            .and_then(|options| options.envs([("LC_ALL", "de_DE.UTF-8")]))
+            .unwrap())
+    );
+    assert!(matches!(
+        Formatter::options().env("", "empty"),
+        Err(Error::ArgumentError { option: "env", .. })
+    ));
+}
+
+#[test]
+fn c_locale() {
+    let script = r#"echo "$LC_ALL $LANG""#;
+    let run = |options: crate::format::FormatterOptions| {
+        options
+            .build()
+            .with_binary(stub("c_locale", script))
+            .format(scratch_file("c_locale"))
+            .unwrap()
+            .stdout_str()
+            .into_owned()
+    };
+    assert_eq!("C C\n", run(Formatter::options()));
+    // Without the C locale, mkfs.btrfs sees this process' own, which can't be
+    // changed here without racing other tests' spawns
+    let parent = |name| std::env::var(name).unwrap_or_default();
+    assert_eq!(
+        format!("{} {}\n", parent("LC_ALL"), parent("LANG")),
+        run(Formatter::options().native_locale().unwrap())
+    );
+    assert_eq!(
+        format!("{} fr_FR.UTF-8\n", parent("LC_ALL")),
+        run(Formatter::options()
+            .native_locale()
+            .and_then(|options| options.env("LANG", "fr_FR.UTF-8"))
+            .unwrap())
+    );
+}
+
+#[test]
+fn free_space_tree_is_verified() {
+    let formatter = |name: &str, stdout: &str| {
+        Formatter::options()
+            .runtime_features(["free-space-tree"])
+            .unwrap()
+            .build()
+            .with_binary(stub(name, &format!("echo '{stdout}'")))
+    };
+    let report = formatter("free_space_tree", "Runtime features:   free-space-tree")
+        .format_reported(scratch_file("free_space_tree"))
+        .unwrap();
+    assert!(report.free_space_tree);
+    let err = formatter("free_space_tree_missing", "Runtime features:   quota")
+        .format_reported(scratch_file("free_space_tree_missing"))
+        .expect_err("free-space-tree wasn't reported");
+    assert!(matches!(err, Error::ReportError(_)), "{err:?}");
+    // Newer versions take it as a regular feature
+    let err = Formatter::options()
+        .features([MkfsFeature::FreeSpaceTree])
+        .unwrap()
+        .build()
+        .with_binary(stub(
+            "free_space_tree_feature",
+            "echo 'Features:           extref, no-holes'",
+        ))
+        .format_reported(scratch_file("free_space_tree_feature"))
+        .expect_err("free-space-tree wasn't reported");
+    assert!(matches!(err, Error::ReportError(_)), "{err:?}");
+}
+
+#[test]
+fn log_to() {
+    let log = scratch_dir().join("log_to.log");
+    let _ = std::fs::remove_file(&log);
+    let formatter = Formatter::options()
+        .log_to(&log)
+        .unwrap()
+        .build()
+        .with_binary(stub(
+            "log_to",
+            "echo 'Label: logged'; echo 'WARNING: stderr' >&2",
+        ));
+    let output = formatter.format(scratch_file("log_to")).unwrap();
+    assert_eq!("Label: logged\n", output.stdout_str());
+    formatter
+        .format_timeout(scratch_file("log_to"), Duration::from_secs(5))
+        .unwrap();
+    assert_eq!(
+        "Label: logged\nWARNING: stderr\n".repeat(2),
+        std::fs::read_to_string(&log).unwrap()
+    );
+    let err = Formatter::options()
+        .log_to(scratch_dir().join("missing").join("log_to.log"))
+        .unwrap()
+        .build()
+        .with_binary(stub("log_to_unopened", "exit 0"))
+        .format(scratch_file("log_to"))
+        .expect_err("The log's directory doesn't exist");
+    assert!(matches!(err, Error::IoError(_)), "{err:?}");
+}
+
+#[test]
+fn format_verified_runs_check() {
+    let device = scratch_file("format_verified_runs_check");
+    let formatter = || {
+        Formatter::options()
+            .build()
+            .with_binary(stub("format_verified_mkfs", "exit 0"))
+    };
+    let output = formatter()
+        .with_check_binary(stub(
+            "format_verified_check",
+            &format!(r#"test "$*" = "check --readonly {}""#, device.display()),
+        ))
+        .format_verified(&device);
+    assert!(output.is_ok(), "{output:?}");
+    let err = formatter()
+        .with_check_binary(stub(
+            "format_verified_broken",
+            "echo 'ERROR: errors found in extent allocation tree' >&2; exit 1",
+        ))
+        .format_verified(&device)
+        .expect_err("The check stub finds problems");
+    assert!(
+        matches!(&err, Error::PostCheckFailed { stderr, .. } if stderr.contains("extent allocation")),
+        "{err:?}"
+    );
+}
+
+#[test]
+#[ignore = "needs mkfs.btrfs and btrfs"]
+fn format_verified() -> crate::Result<()> {
+    let image = scratch_file("format_verified");
+    std::fs::File::options()
+        .write(true)
+        .open(&image)?
+        .set_len(crate::format::DEFAULT_IMAGE_SIZE)?;
+    Formatter::options().build().format_verified(&image)?;
+    Ok(())
+}
+
+#[test]
+fn format_outcome() {
+    use crate::format::FormatOutcome;
+    let outcome = |name: &str, script: &str| {
+        Formatter::options()
+            .build()
+            .with_binary(stub(name, script))
+            .format_outcome(scratch_file(name))
+            .unwrap()
+    };
+    let success = outcome("format_outcome_success", "echo formatted");
+    assert!(matches!(success, FormatOutcome::Success(_)), "{success:?}");
+    assert_eq!("formatted\n", success.output().stdout_str());
+    match outcome("format_outcome_failed", "echo 'ERROR: no' >&2; exit 3") {
+        FormatOutcome::Failed { output, status } => {
+            assert_eq!(Some(3), status.code());
+            assert_eq!("ERROR: no\n", output.stderr_str());
+        }
+        other => panic!("Expected a failure, got {other:?}"),
+    }
+}
+
+#[test]
+fn wipe_signatures() {
+    use std::io::{Seek, SeekFrom, Write};
+    let size = 8 << 20;
+    let device = scratch_file("wipe_signatures");
+    let mut file = std::fs::File::options().write(true).open(&device).unwrap();
+    file.write_all(&vec![0xaa; size]).unwrap();
+    file.seek(SeekFrom::Start(0x1_0040)).unwrap();
+    file.write_all(b"_BHRfS_M").unwrap();
+    drop(file);
+    assert_eq!(
+        Some("btrfs"),
+        crate::device::probe_filesystem(&device).unwrap()
+    );
+
+    let formatter = |options: crate::format::FormatterOptions| {
+        options
+            .build()
+            .with_binary(stub("wipe_signatures", "exit 0"))
+    };
+    formatter(Formatter::options()).format(&device).unwrap();
+    assert_eq!(
+        Some("btrfs"),
+        crate::device::probe_filesystem(&device).unwrap(),
+        "Nothing is wiped without asking"
+    );
+    formatter(Formatter::options().wipe_signatures().unwrap())
+        .format(&device)
+        .unwrap();
+    let wiped = std::fs::read(&device).unwrap();
+    assert_eq!(size, wiped.len());
+    let span = crate::device::WIPE_SPAN as usize;
+    assert!(wiped[..span].iter().all(|&b| b == 0));
+    assert!(wiped[size - span..].iter().all(|&b| b == 0));
+    assert!(wiped[span..size - span].iter().all(|&b| b == 0xaa));
+    assert_eq!(None, crate::device::probe_filesystem(&device).unwrap());
+}
+
+#[test]
+fn heavy_checksum() {
+    use crate::format::{ChecksumAlgorithm, Strictness};
+    let options = Formatter::options()
+        .checksum(ChecksumAlgorithm::Blake2)
+        .and_then(|options| options.sectorsize(4096))
+        .unwrap();
+    let device = scratch_file("heavy_checksum");
+    let warnings = options.clone().build().preflight(&device).unwrap().warnings;
+    assert!(
+        warnings.iter().any(|warning| warning.contains("blake2")),
+        "{warnings:?}"
+    );
+    assert!(options
+        .clone()
+        .checksum_check(Strictness::Ignore)
+        .unwrap()
+        .build()
+        .preflight(&device)
+        .unwrap()
+        .warnings
+        .iter()
+        .all(|warning| !warning.contains("blake2")));
+    options
+        .clone()
+        .try_build()
+        .expect("Only a warning by default");
+    assert!(matches!(
+        options
+            .checksum_check(Strictness::Deny)
+            .unwrap()
+            .try_build(),
+        Err(Error::ArgumentError {
+            option: "checksum",
+            ..
+        })
+    ));
+}
+
+#[test]
+#[cfg(target_os = "linux")]
+fn fill_device() {
+    let device = scratch_file("fill_device");
+    std::fs::File::options()
+        .write(true)
+        .open(&device)
+        .unwrap()
+        .set_len(300 << 20)
+        .unwrap();
+    let formatter = |options: crate::format::FormatterOptions| {
+        options.build().with_binary(stub("fill_device", "exit 0"))
+    };
+    let report = formatter(Formatter::options().fill_device().unwrap())
+        .format_reported(&device)
+        .unwrap();
+    assert_eq!(Some(300 << 20), report.device_size_bytes);
+    let report = formatter(Formatter::options())
+        .format_reported(&device)
+        .unwrap();
+    assert_eq!(None, report.device_size_bytes);
+    assert!(matches!(
+        Formatter::options()
+            .fill_device()
+            .and_then(|options| options.byte_count(1 << 30)),
+        Err(Error::ArgumentError {
+            option: "byte_count",
+            ..
+        })
+    ));
+}
+
+#[test]
+fn format_full() {
+    let (report, elapsed) = Formatter::options()
+        .build()
+        .with_binary(stub(
+            "format_full",
+            "sleep 0.1; echo 'Sector size:        4096'",
+        ))
+        .format_full(scratch_file("format_full"))
+        .unwrap();
+    assert_eq!(Some(4096), report.sector_size);
+    assert!(elapsed >= Duration::from_millis(100), "{elapsed:?}");
+}
+
+#[test]
+fn format_verified_checksum() {
+    use crate::format::ChecksumAlgorithm;
+    let formatter = |checksum: ChecksumAlgorithm| {
+        Formatter::options()
+            .checksum(checksum)
+            .unwrap()
+            .build()
+            .with_binary(stub("format_verified_checksum_mkfs", "exit 0"))
+            .with_check_binary(stub(
+                "format_verified_checksum_dump",
+                r#"test "$1 $2" = "inspect-internal dump-super" && printf 'csum_type\t\t0 (crc32c)\n'"#,
+            ))
+    };
+    let device = scratch_file("format_verified_checksum");
+    formatter(ChecksumAlgorithm::Crc32c)
+        .format_verified_checksum(&device)
+        .unwrap();
+    let err = formatter(ChecksumAlgorithm::Sha256)
+        .format_verified_checksum(&device)
+        .expect_err("The stub reports crc32c");
+    assert!(
+        matches!(
+            err,
+            Error::ChecksumMismatch {
+                expected: ChecksumAlgorithm::Sha256,
+                found: ChecksumAlgorithm::Crc32c
+            }
+        ),
+        "{err:?}"
+    );
+}
+
+#[test]
+fn spawn() {
+    let formatter = Formatter::options()
+        .label("spawned")
+        .unwrap()
+        .build()
+        .with_binary(stub("spawn", r#"echo "$@""#));
+    let device = scratch_file("spawn");
+    let output = formatter
+        .spawn(&device)
+        .unwrap()
+        .wait_with_output()
+        .unwrap();
+    assert!(output.status.success());
+    assert_eq!(
+        format!("--label=spawned {}\n", device.display()),
+        output.stdout_str()
+    );
+    // The device is still checked before anything runs
+    formatter
+        .spawn(scratch_dir().join("spawn_missing"))
+        .expect_err("The device doesn't exist");
+}
+
+#[test]
+fn ensure_formatted() {
+    use std::io::{Seek, SeekFrom, Write};
+    // Writes the btrfs magic, like a real format would
+    let formatter = Formatter::options().build().with_binary(stub(
+        "ensure_formatted",
+        r#"for device; do :; done; printf _BHRfS_M | dd of="$device" bs=1 seek=65600 conv=notrunc 2>/dev/null"#,
+    ));
+    let blank = scratch_file("ensure_formatted_blank");
+    assert!(formatter.ensure_formatted(&blank).unwrap());
+    assert!(
+        !formatter.ensure_formatted(&blank).unwrap(),
+        "Formatted once already"
+    );
+
+    let formatted = scratch_file("ensure_formatted_existing");
+    let mut file = std::fs::File::options()
+        .write(true)
+        .open(&formatted)
+        .unwrap();
+    file.set_len(1 << 20).unwrap();
+    file.seek(SeekFrom::Start(0x1_0040)).unwrap();
+    file.write_all(b"_BHRfS_M").unwrap();
+    drop(file);
+    let never = Formatter::options()
+        .build()
+        .with_binary(stub("ensure_formatted_never", "exit 1"));
+    assert!(!never.ensure_formatted(&formatted).unwrap());
+    never
+        .ensure_formatted(scratch_file("ensure_formatted_fails"))
+        .expect_err("The stub fails");
+}
@@ -0,0 +1,190 @@
+//! Consistency checks across [`FormatterOptions`](crate::format::FormatterOptions)
+//!
+//! Each setter rejects values that are wrong on their own; the checks here catch
+//! options that are fine alone but conflict with each other. Use
+//! [`FormatterOptions::violations`](crate::format::FormatterOptions::violations)
+//! to get every problem at once, e.g. to show them all in a UI.
+
+use crate::format::{ChecksumAlgorithm, DataProfile};
+use thiserror::Error;
+
+/// A conflict between options, found by
+/// [`FormatterOptions::violations`](crate::format::FormatterOptions::violations)
+#[derive(Error, Debug, Clone, PartialEq, Eq)]
+pub enum Violation {
+    /// `sectorsize` isn't a power of 2 from 4096 to 65536
+    #[error("sectorsize ( = {0} )\nMust be a power of 2, from 4096 to 65536")]
+    SectorsizeRange(usize),
+    /// `sectorsize` differs from this system's page size, and
+    /// [`page_size_check`](crate::format::FormatterOptions::page_size_check) denies that
+    #[error(
+        "sectorsize ( = {sectorsize} ) differs from this system's page size ( = {page_size} ); \
+         the filesystem may not mount here"
+    )]
+    PageSizeMismatch { sectorsize: usize, page_size: usize },
+    /// `shrink` was set without a rootdir to shrink to
+    #[error("{}", crate::format::SHRINK_WITHOUT_ROOTDIR)]
+    ShrinkWithoutRootdir,
+    /// `nodesize` is smaller than `sectorsize`
+    #[error("nodesize ( = {nodesize} ), sectorsize ( = {sectorsize} )\nMust not be smaller than sectorsize")]
+    NodesizeBelowSectorsize { nodesize: usize, sectorsize: usize },
+    /// Data and metadata use different profiles in mixed mode
+    #[error(
+        "data ( = {data} ), metadata ( = {metadata} )\nMust be the same profile in mixed mode"
+    )]
+    MixedProfiles {
+        data: DataProfile,
+        metadata: DataProfile,
+    },
+    /// `nodesize` and `sectorsize` differ in mixed mode
+    #[error(
+        "nodesize ( = {nodesize} ), sectorsize ( = {sectorsize} )\nMust be equal in mixed mode"
+    )]
+    MixedBlockSizes { nodesize: usize, sectorsize: usize },
+    /// `byte_count` is too small for a profile, outside of mixed mode
+    #[error(
+        "byte_count ( = {byte_count} ), {profile} profile\n\
+         Must be at least {minimum} bytes; use mixed mode for smaller filesystems"
+    )]
+    ByteCountTooSmall {
+        byte_count: u64,
+        profile: DataProfile,
+        minimum: u64,
+    },
+    /// `device_uuid` is the same as the filesystem `uuid`
+    #[error("device_uuid ( = {0} )\nMust differ from the filesystem uuid")]
+    DeviceUuidMatchesUuid(String),
+    /// A 32-byte checksum is paired with the smallest `sectorsize`, and
+    /// [`checksum_check`](crate::format::FormatterOptions::checksum_check) denies that
+    #[error(
+        "checksum ( = {checksum} ), sectorsize ( = {sectorsize} )\n\
+         {} byte checksums on {sectorsize} byte sectors take up more metadata, and may be slow",
+        checksum.size()
+    )]
+    HeavyChecksum {
+        checksum: ChecksumAlgorithm,
+        sectorsize: usize,
+    },
+}
+
+impl Violation {
+    /// The option this violation is reported against
+    ///
+    /// # Examples
+    /// ```
+    /// use mkfs_btrfs_rs::validate::Violation;
+    /// assert_eq!("shrink", Violation::ShrinkWithoutRootdir.option());
+    /// ```
+    pub fn option(&self) -> &'static str {
+        use Violation::*;
+        match self {
+            SectorsizeRange(_) | PageSizeMismatch { .. } => "sectorsize",
+            ShrinkWithoutRootdir => "shrink",
+            NodesizeBelowSectorsize { .. } => "nodesize",
+            MixedProfiles { .. } | MixedBlockSizes { .. } => "mixed",
+            ByteCountTooSmall { .. } => "byte_count",
+            DeviceUuidMatchesUuid(_) => "device_uuid",
+            HeavyChecksum { .. } => "checksum",
+        }
+    }
+}
+
+impl From<Violation> for crate::Error {
+    fn from(violation: Violation) -> Self {
+        crate::Error::ArgumentError {
+            option: violation.option(),
+            message: violation.to_string(),
+        }
+    }
+}
+
+/// What the checks need to know about a set of options
+pub(crate) struct Settings {
+    pub byte_count: Option<u64>,
+    /// A checksum and sector size that should be denied together
+    pub heavy_checksum: Option<(ChecksumAlgorithm, usize)>,
+    pub sectorsize: Option<usize>,
+    pub nodesize: Option<usize>,
+    pub data: Option<DataProfile>,
+    pub metadata: Option<DataProfile>,
+    /// The page size, if a mismatch with it should be denied
+    pub deny_page_size: Option<usize>,
+    /// Whether shrinking without a rootdir should be denied
+    pub deny_shrink: bool,
+    pub mixed: bool,
+    pub shrink: bool,
+    pub rootdir: bool,
+    pub uuid: Option<String>,
+    pub device_uuid: Option<String>,
+}
+
+/// Collect every violation in `settings`, in the order they're checked
+pub(crate) fn violations(settings: &Settings) -> Vec<Violation> {
+    use Violation::*;
+    let mut found = Vec::new();
+    if let Some(sectorsize) = settings.sectorsize {
+        if !sectorsize.is_power_of_two()
+            || !(4096..=crate::format::MAX_SECTORSIZE).contains(&sectorsize)
+        {
+            found.push(SectorsizeRange(sectorsize));
+        }
+        if let Some(page_size) = settings.deny_page_size.filter(|&p| p != sectorsize) {
+            found.push(PageSizeMismatch {
+                sectorsize,
+                page_size,
+            });
+        }
+    }
+    if settings.deny_shrink && settings.shrink && !settings.rootdir {
+        found.push(ShrinkWithoutRootdir);
+    }
+    if let (Some(nodesize), Some(sectorsize)) = (settings.nodesize, settings.sectorsize) {
+        if nodesize < sectorsize {
+            found.push(NodesizeBelowSectorsize {
+                nodesize,
+                sectorsize,
+            });
+        }
+    }
+    if settings.mixed {
+        if let (Some(data), Some(metadata)) = (settings.data, settings.metadata) {
+            if data != metadata {
+                found.push(MixedProfiles { data, metadata });
+            }
+        }
+        if let (Some(nodesize), Some(sectorsize)) = (settings.nodesize, settings.sectorsize) {
+            if nodesize != sectorsize {
+                found.push(MixedBlockSizes {
+                    nodesize,
+                    sectorsize,
+                });
+            }
+        }
+    } else if let Some(byte_count) = settings.byte_count {
+        // Without a metadata profile, mkfs.btrfs uses dup on a single device
+        let metadata = settings.metadata.unwrap_or(DataProfile::Dup);
+        let strictest = [settings.data, Some(metadata)]
+            .into_iter()
+            .flatten()
+            .max_by_key(DataProfile::min_byte_count);
+        if let Some(profile) = strictest.filter(|p| byte_count < p.min_byte_count()) {
+            found.push(ByteCountTooSmall {
+                byte_count,
+                profile,
+                minimum: profile.min_byte_count(),
+            });
+        }
+    }
+    if let (Some(uuid), Some(device_uuid)) = (&settings.uuid, &settings.device_uuid) {
+        if uuid.eq_ignore_ascii_case(device_uuid) {
+            found.push(DeviceUuidMatchesUuid(device_uuid.clone()));
+        }
+    }
+    if let Some((checksum, sectorsize)) = settings.heavy_checksum {
+        found.push(HeavyChecksum {
+            checksum,
+            sectorsize,
+        });
+    }
+    found
+}